@@ -0,0 +1,43 @@
+extern crate broker;
+use broker::{Event, decode_event, encode_event_as};
+use serde_json::json;
+use std::time::Instant;
+use uuid::Uuid;
+
+// compare the scan throughput of json and binary encoded events - run with: cargo bench --bench scan
+fn main() {
+
+    let count = 100_000;
+    let tenant_id = Uuid::new_v4();
+    let events : Vec<Event> = (0..count).map(|i| {
+        Event{id: Uuid::new_v4(), user_id: Uuid::new_v4(), collection_id: Uuid::new_v4(), tenant_id: tenant_id, event: "presence".to_owned(), timestamp: 1578667309 + i, published: true, cancelled: false, data: json!({"name": "rust", "status": "online", "count": i, "tags": ["a", "b", "c"]}), expires_at: None, expired: false}
+    }).collect();
+
+    for (name, binary) in vec![("json", false), ("binary", true)] {
+        let tree = sled::Config::new().temporary(true).open().unwrap();
+        let mut size = 0;
+        for evt in events.iter() {
+            let bytes = encode_event_as(evt, binary);
+            size += bytes.len();
+            tree.insert(format!("_v_{}", evt.id).as_bytes(), bytes).unwrap();
+        }
+
+        // scan and decode all events like a collection read does
+        let rounds = 5;
+        let started = Instant::now();
+        for _ in 0..rounds {
+            let mut found = 0;
+            for x in tree.scan_prefix("_v_") {
+                let p = x.unwrap();
+                let evt = decode_event(&p.1);
+                if evt.tenant_id == tenant_id {
+                    found += 1;
+                }
+            }
+            assert_eq!(found, count);
+        }
+        let elapsed = started.elapsed();
+        let per_sec = (count as f64 * rounds as f64) / elapsed.as_secs_f64();
+        println!("{:>6}: {:>10.0} events/s scanned - {:>4} bytes/event", name, per_sec, size / count as usize);
+    }
+}
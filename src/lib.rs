@@ -1,7 +1,9 @@
+#![recursion_limit = "256"]
+
 use tokio::stream::StreamExt;
 use tokio::time::interval;
 use std::iter::Iterator;
-use std::collections::{HashSet, HashMap, VecDeque};
+use std::collections::{HashSet, HashMap};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 use uuid::Uuid;
@@ -16,18 +18,89 @@ use crossbeam::channel::unbounded;
 use inflector::Inflector;
 use json_patch::merge;
 use std::sync::{Arc, Mutex};
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use base64::{decode as base64_decode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use base64::{decode as base64_decode, encode as base64_encode};
+use chrono::TimeZone;
+use std::str::FromStr;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+mod store;
+pub use store::{Store, Batch, Entry, SledStore, MemoryStore};
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+// the store of the broker
+fn store() -> &'static dyn Store {
+    STORE.as_ref()
+}
 
-// init database as lazy
+// version of the storage layout - bump it with a new migration when the layout of the records changes
+const STORAGE_VERSION: u64 = 5;
+
+// seconds a claimed idempotency key waits for its event before another request may claim it again
+const IDEMPOTENCY_GRACE: i64 = 5;
+
+// init store as lazy - sled by default, memory or sqlite
 lazy_static! {
-    static ref TREE: HashMap<String, sled::Db> = {
+    static ref STORE: Box<dyn Store> = {
         let configure = config();
-        let tree = sled::open(configure.save_path).unwrap();
-        let mut m = HashMap::new();
-        m.insert("tree".to_owned(), tree);
-        m
+        match configure.store.as_str() {
+            "memory" => Box::new(MemoryStore::default()),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Box::new(SqliteStore::open(&configure.save_path).unwrap()),
+            #[cfg(not(feature = "sqlite"))]
+            "sqlite" => panic!("the sqlite store needs the broker built with --features sqlite"),
+            _ => Box::new(SledStore::open(&configure.save_path).unwrap())
+        }
     };
+
+    // set when there are writes not yet flushed by group commit
+    static ref DIRTY: AtomicBool = AtomicBool::new(false);
+
+    // whether events are written in the binary encoding - chosen when the store is created
+    static ref BINARY: bool = store_encoding(store()) == "binary";
+
+    // the open event streams per tenant
+    static ref SUBSCRIBERS: Mutex<HashMap<uuid::Uuid, usize>> = Mutex::new(HashMap::new());
+
+    // the tenants being purged or purged since the start (until they are created again) - their event streams are closed
+    static ref PURGING: Mutex<HashSet<uuid::Uuid>> = Mutex::new(HashSet::new());
+}
+
+// an open event stream of a tenant - counted until it is dropped
+struct Subscription {
+    tenant_id: uuid::Uuid,
+}
+
+impl Subscription {
+    // open a subscription unless the tenant already has its maximum of subscribers
+    fn open(tenant_id: uuid::Uuid, max_subscribers: Option<usize>) -> Option<Subscription> {
+        let mut subscribers = SUBSCRIBERS.lock().unwrap();
+        let count = subscribers.entry(tenant_id).or_insert(0);
+        if max_subscribers.map_or(false, |max_subscribers| *count >= max_subscribers) {
+            return None
+        }
+        *count += 1;
+        Some(Subscription{tenant_id: tenant_id})
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut subscribers = SUBSCRIBERS.lock().unwrap();
+        if let Some(count) = subscribers.get_mut(&self.tenant_id) {
+            *count -= 1;
+        }
+    }
+}
+
+// the open event streams of a tenant
+fn subscribers(tenant_id: uuid::Uuid) -> usize {
+    *SUBSCRIBERS.lock().unwrap().get(&tenant_id).unwrap_or(&0)
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -44,11 +117,26 @@ pub struct Record {
 pub struct UserCollection {
     pub info: Vec<Event>,
     pub events: Vec<Event>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Collection {
     pub events: Vec<Event>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Page {
+    cursor: Option<String>,
+    limit: Option<usize>,
+    from: Option<i64>,
+    to: Option<i64>,
+    event: Option<String>,
+    include_cancelled: Option<bool>,
+    order: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -70,6 +158,19 @@ pub struct Config {
   pub connection: String,
   pub cert_path: String,
   pub key_path: String,
+  pub idempotency_window: i64,
+  pub durability: String,
+  pub group_commit_ms: u64,
+  pub admin_secret: String,
+  pub dry_run: bool,
+  pub encoding: String,
+  pub store: String,
+  pub retention_interval: u64,
+  pub archive_path: String,
+  pub webhook_attempts: u32,
+  pub webhook_backoff: i64,
+  pub webhook_allow_private: bool,
+  pub command: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -108,12 +209,186 @@ pub struct Claims {
     exp: usize,
 }
 
+// a line of a backup - users and events are typed and every other record is kept as is
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackupLine {
+    User{value: User},
+    Event{value: Event},
+    Record{key: String, value: String},
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ExportQuery {
+    collection_id: Option<uuid::Uuid>,
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ImportQuery {
+    tenant_id: Option<uuid::Uuid>,
+    keep_ids: Option<bool>,
+}
+
+// retention rules of a tenant - events past any of the limits are pruned by the retention task (archived first when archive is set)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RetentionPolicy {
+    max_age: Option<i64>,
+    max_events: Option<usize>,
+    keep_latest: Option<usize>,
+    archive: Option<bool>,
+}
+
+// a tenant with its settings - users and events can only reference existing tenants
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tenant {
+    pub id: uuid::Uuid,
+    pub name: String,
+    #[serde(default)]
+    pub settings: TenantSettings,
+}
+
+// settings of a tenant - origin is the browser origin allowed to open its event stream
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TenantSettings {
+    origin: Option<String>,
+    retention: Option<RetentionPolicy>,
+    limits: Option<TenantLimits>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TenantLimits {
+    max_users: Option<usize>,
+    events_per_day: Option<u64>,
+    storage_bytes: Option<u64>,
+    max_payload_bytes: Option<usize>,
+    max_subscribers: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Usage {
+    pub tenant_id: uuid::Uuid,
+    pub users: usize,
+    pub events: usize,
+    pub events_today: u64,
+    pub storage_bytes: u64,
+    pub subscribers: usize,
+    pub limits: Option<TenantLimits>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TenantForm {
+    id: Option<uuid::Uuid>,
+    name: String,
+    settings: Option<TenantSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TenantUpdateForm {
+    name: Option<String>,
+    settings: Option<TenantSettings>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Tenants {
+    pub tenants: Vec<Tenant>,
+}
+
+// progress of the purge of a tenant's data - status is running, done or failed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Purge {
+    pub tenant_id: uuid::Uuid,
+    pub status: String,
+    pub total: usize,
+    pub deleted: usize,
+    pub error: Option<String>,
+}
+
+// a user without the password hash
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserInfo {
+    pub id: uuid::Uuid,
+    pub username: String,
+    pub collection_id: uuid::Uuid,
+    pub tenant_id: uuid::Uuid,
+}
+
+// everything a user has inserted - the events of the user's collection (info), the events authored by the user and the user's recurrences
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserExport {
+    pub user: UserInfo,
+    pub info: Vec<Event>,
+    pub events: Vec<Event>,
+    pub recurrences: Vec<Recurrence>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ErasureQuery {
+    events: Option<String>,
+}
+
+// a webhook of a tenant - published events with one of the event names (all when empty) are posted to the url signed with the secret
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub id: uuid::Uuid,
+    pub tenant_id: uuid::Uuid,
+    pub url: String,
+    pub events: Vec<String>,
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookForm {
+    url: String,
+    events: Option<Vec<String>>,
+    secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Webhooks {
+    pub webhooks: Vec<Webhook>,
+}
+
+// a published event to post to a webhook - with the attempts so far, when it is tried next and why the last attempt failed
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Delivery {
+    pub id: uuid::Uuid,
+    pub webhook_id: uuid::Uuid,
+    pub tenant_id: uuid::Uuid,
+    pub event: Event,
+    pub attempts: u32,
+    pub next_at: i64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeadLetters {
+    pub dead_letters: Vec<Delivery>,
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Cfg {
   pub save_path: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// an event in the binary encoding - the free-form data is kept as embedded json bytes
+#[derive(Debug, Serialize, Deserialize)]
+struct BinaryEvent {
+    id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    collection_id: uuid::Uuid,
+    tenant_id: uuid::Uuid,
+    event: String,
+    timestamp: i64,
+    published: bool,
+    cancelled: bool,
+    data: Vec<u8>,
+    expires_at: Option<i64>,
+    expired: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct Event {
     pub id: uuid::Uuid,
     pub user_id: uuid::Uuid,
@@ -124,6 +399,10 @@ pub struct Event {
     pub published: bool,
     pub cancelled: bool,
     pub data: serde_json::Value,
+    #[serde(default)]
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub expired: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -133,52 +412,238 @@ pub struct EventForm {
     event: String,
     timestamp: i64,
     data: serde_json::Value,
+    expires_at: Option<i64>,
+    ttl: Option<i64>,
 }
 
-// helper function to create sse events
-fn get_events(tenant_id: uuid::Uuid) -> Vec<SSE> {
-    let tree = TREE.get(&"tree".to_owned()).unwrap();
-    let mut vals : Vec<Event> = tree.iter().into_iter().filter(|x| {
-        let p = x.as_ref().unwrap();
-        let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-        if k.contains("_v_") {
-            let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-            let evt : Event = serde_json::from_str(&v).unwrap();
-            if !evt.cancelled && evt.tenant_id == tenant_id {
-                return true
-            } else {
-                return false
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdempotencyKey {
+    event_id: uuid::Uuid,
+    created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchQuery {
+    atomic: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RescheduleForm {
+    timestamp: i64,
+    data: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Recurrence {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub collection_id: uuid::Uuid,
+    pub tenant_id: uuid::Uuid,
+    pub event: String,
+    pub data: serde_json::Value,
+    pub cron: Option<String>,
+    pub frequency: Option<String>,
+    pub interval: i64,
+    pub timezone: String,
+    pub starts_at: i64,
+    pub ends_at: Option<i64>,
+    pub count: Option<u64>,
+    pub occurrences: u64,
+    pub next_at: Option<i64>,
+    pub next_event_id: Option<uuid::Uuid>,
+    pub paused: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecurrenceForm {
+    collection_id: uuid::Uuid,
+    tenant_id: uuid::Uuid,
+    event: String,
+    data: serde_json::Value,
+    cron: Option<String>,
+    frequency: Option<String>,
+    interval: Option<i64>,
+    timezone: Option<String>,
+    starts_at: Option<i64>,
+    ends_at: Option<i64>,
+    count: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Recurrences {
+    pub recurring: Vec<Recurrence>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Column {
+    pub field: String,
+    pub title: String,
+    #[serde(rename = "type", default)]
+    pub column_type: String,
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Columns {
+    pub columns: Vec<Column>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ColumnForm {
+    event: String,
+    columns: Vec<Column>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum QueryFilter {
+    And { and: Vec<QueryFilter> },
+    Or { or: Vec<QueryFilter> },
+    Predicate(Predicate),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Predicate {
+    field: String,
+    eq: Option<serde_json::Value>,
+    gt: Option<serde_json::Value>,
+    gte: Option<serde_json::Value>,
+    lt: Option<serde_json::Value>,
+    lte: Option<serde_json::Value>,
+    #[serde(rename = "in")]
+    one_of: Option<Vec<serde_json::Value>>,
+    exists: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QueryForm {
+    filter: QueryFilter,
+    #[serde(flatten)]
+    page: Page,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IndexForm {
+    field: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Indexes {
+    pub indexes: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Metric {
+    op: String,
+    field: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AggregateForm {
+    filter: Option<QueryFilter>,
+    from: Option<i64>,
+    to: Option<i64>,
+    include_cancelled: Option<bool>,
+    group_by: Option<String>,
+    bucket: Option<String>,
+    metrics: Vec<Metric>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Aggregate {
+    pub bucket: Option<i64>,
+    pub group: serde_json::Value,
+    pub metrics: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Aggregates {
+    pub results: Vec<Aggregate>,
+}
+
+
+// key of the column definition for an event name of a tenant (event names are free-form so are base64ed)
+fn columns_key(tenant_id: uuid::Uuid, event: &str) -> String {
+    format!("_c_{}_{}", tenant_id, base64_encode(event))
+}
+
+// get the stored column definition for an event name of a tenant
+fn get_columns(tree: &dyn Store, tenant_id: uuid::Uuid, event: &str) -> Option<Vec<Column>> {
+    match tree.get(columns_key(tenant_id, event).as_bytes()).unwrap() {
+        Some(g) => {
+            let v = std::str::from_utf8(&g).unwrap().to_owned();
+            let c : Columns = serde_json::from_str(&v).unwrap();
+            Some(c.columns)
+        },
+        None => None
+    }
+}
+
+// name the json type of a value
+fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Object(_) => "object",
+    }
+}
+
+// infer the columns from the data keys of events and the types of their values
+fn infer_columns(evts: &Vec<Event>) -> Vec<Column> {
+    let mut types : HashMap<String, HashSet<&'static str>> = HashMap::new();
+    for evt in evts {
+        for (k, v) in evt.data.as_object().unwrap() {
+            let kinds = types.entry(k.clone()).or_insert(HashSet::new());
+            if !v.is_null() {
+                kinds.insert(json_type(v));
             }
-        } else {
-            return false
         }
-    }).map(|x| {
-        let p = x.as_ref().unwrap();
-        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-        let evt : Event = serde_json::from_str(&v).unwrap();
-        evt
-    }).collect();
+    }
 
-    vals.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    let mut columns : Vec<Column> = Vec::new();
+    for (field, kinds) in types {
+        if field != "collection_id" && field != "timestamp" {
+            let column_type = match kinds.len() {
+                0 => "null".to_owned(),
+                1 => kinds.iter().next().unwrap().to_string(),
+                _ => "mixed".to_owned()
+            };
+            columns.push(Column{title: Inflector::to_sentence_case(&field), field: field, column_type: column_type, hidden: false, format: None});
+        }
+    }
+    columns.sort_by(|a, b| a.field.cmp(&b.field));
+
+    columns.insert(0, Column{title: "collection_id".to_owned(), field: "collection_id".to_owned(), column_type: "string".to_owned(), hidden: false, format: None});
+    columns.insert(0, Column{title: "Timestamp".to_owned(), field: "timestamp".to_owned(), column_type: "timestamp".to_owned(), hidden: false, format: None});
+    columns
+}
+
+// helper function to create sse events
+fn get_events(tenant_id: uuid::Uuid) -> Vec<SSE> {
+    let tree = store();
 
-    let mut uniques : HashSet<String> = HashSet::new();
-    for evt in &vals {
-        uniques.insert(evt.clone().event);
+    // group the materialized latest events per collection by event name
+    let mut latest : HashMap<String, Vec<Event>> = HashMap::new();
+    for evt in get_state(tree, tenant_id, None) {
+        latest.entry(evt.event.clone()).or_insert(Vec::new()).push(evt);
     }
 
     let mut sse_events : Vec<SSE> = Vec::new();
 
-    for evt in uniques {
-        let mut events : HashMap<String, Event> = HashMap::new();
-        for event in &vals {
-            if evt == event.event {
-                events.insert(event.clone().collection_id.to_string(), event.clone());
-            }
-        }
+    for (evt, events) in latest {
         let mut evts : Vec<Event> = Vec::new();
-        let mut uniq_data_keys : HashSet<String> = HashSet::new();
         let mut rows : Vec<serde_json::Value> = Vec::new();
-        for (_, v) in events {
+        for v in events {
             if v.clone().data.is_object() {
                 evts.push(v.clone());
                 let mut data = v.clone().data;
@@ -187,30 +652,17 @@ fn get_events(tenant_id: uuid::Uuid) -> Vec<SSE> {
                 let j = json!({"collection_id": v.clone().collection_id});
                 merge(&mut data, &j);
                 rows.push(data);
-                for (k, _) in v.clone().data.as_object().unwrap() {
-                    uniq_data_keys.insert(k.clone());
-                }
             }
         }
 
         rows.sort_by(|a, b| a.get("timestamp").unwrap().to_string().cmp(&b.get("timestamp").unwrap().to_string()));
         rows.reverse();
 
-        let mut columns : VecDeque<serde_json::Value> = VecDeque::new();
-        for uniq_key in uniq_data_keys {
-            if uniq_key != "collection_id" && uniq_key != "timestamp" {
-                columns.push_back(json!({"title": Inflector::to_sentence_case(&uniq_key), "field": uniq_key}));
-            }
-        }
-
-        let mut cols : Vec<&serde_json::Value> = columns.iter().collect();
-        cols.sort_by(|a, b| a.to_string().cmp(&b.to_string()));
-        let mut colz : VecDeque<serde_json::Value> = VecDeque::new();
-        for col in cols {
-            colz.push_back(col.clone());
-        }
-        colz.push_front(json!({"title": "collection_id", "field": "collection_id"}));
-        colz.push_front(json!({"title": "Timestamp", "field": "timestamp"}));
+        // use the tenant's column definition for the event or fallback to inference
+        let colz = match get_columns(tree, tenant_id, &evt) {
+            Some(columns) => columns,
+            None => infer_columns(&evts)
+        };
 
         let guid = Uuid::new_v4().to_string();
         let events_json = json!({"events": evts, "columns": colz, "rows": rows});
@@ -219,158 +671,315 @@ fn get_events(tenant_id: uuid::Uuid) -> Vec<SSE> {
     sse_events
 }
 
-// get ntp time from global servers (cloudflare primary and fallback pool)
-pub fn get_ntp_time() -> i64 {
-    let pool_ntp = "pool.ntp.org:123";
-    let cf_ntp = "time.cloudflare.com:123";
-    let response = match broker_ntp::request(cf_ntp) {
-        Ok(res) => res,
-        Err(_) => broker_ntp::request(pool_ntp).unwrap()
+// key of the latest event of a collection for an event name of a tenant in the materialized state
+fn state_key(tenant_id: uuid::Uuid, event: &str, collection_id: uuid::Uuid) -> String {
+    format!("_s_{}_{}_{}", tenant_id, base64_encode(event), collection_id)
+}
+
+// get the materialized latest events per collection of a tenant - for all event names or only one
+fn get_state(tree: &dyn Store, tenant_id: uuid::Uuid, event: Option<&str>) -> Vec<Event> {
+    let prefix = match event {
+        Some(event) => format!("_s_{}_{}_", tenant_id, base64_encode(event)),
+        None => format!("_s_{}_", tenant_id)
     };
-    let timestamp = response.transmit_timestamp;
-    broker_ntp::unix_time::Instant::from(timestamp).secs()
+    tree.scan_prefix(prefix.as_bytes()).map(|x| {
+        let p = x.unwrap();
+        let evt = decode_event(&p.1);
+        evt
+    }).collect()
 }
 
-// cancel future event
-fn cancel(tree: sled::Db, event_id: String, user_id: String) -> String {
+// make a published event the latest of its collection in the materialized state unless a later one is already there
+fn update_state(tree: &dyn Store, evt: &Event) {
+    let key = state_key(evt.tenant_id, &evt.event, evt.collection_id);
+    let later = match tree.get(key.as_bytes()).unwrap() {
+        Some(g) => {
+            let current = decode_event(&g);
+            (current.timestamp, current.id) > (evt.timestamp, evt.id)
+        },
+        None => false
+    };
+    if !later {
+        let _ = tree.insert(key.as_bytes(), &encode_event(evt));
+    }
+}
 
-    let versioned = format!("_u_{}", user_id);
-    let g = tree.get(&versioned.as_bytes()).unwrap().unwrap();
-    let v = std::str::from_utf8(&g).unwrap().to_owned();
-    let user : User = serde_json::from_str(&v).unwrap();
+// recompute the latest event of a collection in the materialized state from the stored events (after the latest one is cancelled or expired)
+fn refresh_state(tree: &dyn Store, tenant_id: uuid::Uuid, event: &str, collection_id: uuid::Uuid) {
+    let key = state_key(tenant_id, event, collection_id);
+    let latest = tree.tenant_events(tenant_id).into_iter().filter(|evt| {
+        evt.published && !evt.cancelled && !evt.expired && evt.event == event && evt.collection_id == collection_id
+    }).max_by(|a, b| (a.timestamp, a.id).cmp(&(b.timestamp, b.id)));
+    let _ = match latest {
+        Some(evt) => tree.insert(key.as_bytes(), &encode_event(&evt)),
+        None => tree.remove(key.as_bytes())
+    };
+}
 
-    let versioned = format!("_v_{}", event_id);
-    let g = tree.get(&versioned.as_bytes()).unwrap().unwrap();
-    let v = std::str::from_utf8(&g).unwrap().to_owned();
-    let mut json : Event = serde_json::from_str(&v).unwrap();
-    let j = json.clone();
-    if json.tenant_id == user.tenant_id {
-        json.cancelled = true;
-        let _ = tree.compare_and_swap(versioned.as_bytes(), Some(serde_json::to_string(&j).unwrap().as_bytes()), Some(serde_json::to_string(&json).unwrap().as_bytes()));
-        let _ = tree.flush();
+// rebuild the materialized state from the stored events
+fn rebuild_state(tree: &dyn Store) {
+    for x in tree.scan_prefix(b"_s_") {
+        let p = x.unwrap();
+        let _ = tree.remove(&p.0);
+    }
+    for x in tree.scan_prefix(b"_v_") {
+        let p = x.unwrap();
+        let evt = decode_event(&p.1);
+        if evt.published && !evt.cancelled && !evt.expired {
+            update_state(tree, &evt);
+        }
     }
-    json!({"event": json}).to_string()
+    persist(tree);
 }
 
-// display user collection of events
-fn user_collection(tree: sled::Db, id: String) -> String {
-
-    let versioned = format!("_u_{}", id);
-    let g = tree.get(&versioned.as_bytes()).unwrap().unwrap();
-    let v = std::str::from_utf8(&g).unwrap().to_owned();
-    let user : User = serde_json::from_str(&v).unwrap();
+// mark an event as expired and remove it from the materialized state - returns the event if it was expired now
+fn expire(tree: &dyn Store, event_id: &str) -> Option<Event> {
+    let old = tree.get_event(event_id)?;
+    if old.cancelled || old.expired {
+        return None
+    }
+    let mut evt = old.clone();
+    evt.expired = true;
 
-    // turn iVec(s) to String(s) and make HashMap
-    let mut info: Vec<Event> = tree.iter().into_iter().filter(|x| {
-        let p = x.as_ref().unwrap();
-        let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-        if k.contains(&"_v_") {
-            let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-            let j : Event = serde_json::from_str(&v).unwrap();
-            if j.collection_id.to_string() == user.collection_id.to_string() {
-                return true
-            } else {
-                return false
-            }
-        } else {
-            return false
+    if tree.swap_event(&old, &evt) {
+        let _ = tree.remove(pending_key(&evt).as_bytes());
+        let latest = get_state(tree, evt.tenant_id, Some(&evt.event)).into_iter().any(|e| e.id == evt.id);
+        if latest {
+            refresh_state(tree, evt.tenant_id, &evt.event, evt.collection_id);
         }
-    }).map(|x| {
-        let p = x.unwrap();
-        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-        let j : Event = serde_json::from_str(&v).unwrap();
-        j
-    }).collect();
+        persist(tree);
+        return Some(evt)
+    }
+    None
+}
 
-    info.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+// display the latest events per collection of an event name for the user's tenant
+fn state(tree: &dyn Store, user_id: String, event: String) -> (StatusCode, String) {
 
-    // turn iVec(s) to String(s) and make HashMap
-    let mut owned: Vec<Event> = tree.iter().into_iter().filter(|x| {
-        let p = x.as_ref().unwrap();
-        let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-        if k.contains(&"_v_") {
-            let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-            let j : Event = serde_json::from_str(&v).unwrap();
-            if j.user_id.to_string() == user.id.to_string() {
-                return true
-            } else {
-                return false
-            }
-        } else {
-            return false
-        }
-    }).map(|x| {
-        let p = x.unwrap();
-        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-        let j : Event = serde_json::from_str(&v).unwrap();
-        j
-    }).collect();
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let mut records = get_state(tree, user.tenant_id, Some(&event));
+    records.sort_by(|a, b| (a.timestamp, a.id).cmp(&(b.timestamp, b.id)));
 
-    owned.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    let c = Collection{events: records, next_cursor: None};
+    (StatusCode::OK, serde_json::to_string(&c).unwrap())
+}
 
-    let c = UserCollection{info: info, events: owned};
-    let data : String = serde_json::to_string(&c).unwrap();
-    data
+// key of an event in the pending queue - ordered by timestamp (past timestamps are all due so are clamped to zero)
+fn pending_key(evt: &Event) -> String {
+    format!("_p_{:020}_{}", std::cmp::max(evt.timestamp, 0), evt.id)
 }
 
-// display collection of events based on collection_id
-fn collection(tree: sled::Db, collection_id: String, user_id: String) -> String {
- 
-    let versioned = format!("_u_{}", user_id);
-    let g = tree.get(&versioned.as_bytes()).unwrap().unwrap();
-    let v = std::str::from_utf8(&g).unwrap().to_owned();
-    let user : User = serde_json::from_str(&v).unwrap();
-
-    let mut records: Vec<Event> = tree.iter().into_iter().filter(|x| {
-        let p = x.as_ref().unwrap();
-        let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-        if k.contains(&"_v_") {
-            let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-            let j : Event = serde_json::from_str(&v).unwrap();
-            if j.collection_id.to_string() == collection_id && j.tenant_id == user.tenant_id {
-                return true
-            } else {
-                return false
+// key of an event in the expiry queue - ordered by expiry like the pending queue
+fn expiry_key(evt: &Event) -> Option<String> {
+    evt.expires_at.map(|expires_at| format!("_e_{:020}_{}", std::cmp::max(expires_at, 0), evt.id))
+}
+
+// rebuild the pending and expiry queues from the stored events
+fn rebuild_pending(tree: &dyn Store) {
+    for x in tree.scan_prefix(b"_p_").chain(tree.scan_prefix(b"_e_")) {
+        let p = x.unwrap();
+        let _ = tree.remove(&p.0);
+    }
+    for x in tree.scan_prefix(b"_v_") {
+        let p = x.unwrap();
+        let evt = decode_event(&p.1);
+        if !evt.published && !evt.cancelled && !evt.expired {
+            let _ = tree.insert(pending_key(&evt).as_bytes(), evt.id.to_string().as_bytes());
+        }
+        if let Some(key) = expiry_key(&evt) {
+            if !evt.cancelled && !evt.expired {
+                let _ = tree.insert(key.as_bytes(), evt.id.to_string().as_bytes());
             }
-        } else {
-            return false
         }
-    }).map(|x| {
-        let p = x.unwrap();
-        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-        let j : Event = serde_json::from_str(&v).unwrap();
-        j
-    }).collect();
+    }
+    persist(tree);
+}
+
+// get ntp time from global servers (cloudflare primary and fallback pool)
+pub fn get_ntp_time() -> i64 {
+    let pool_ntp = "pool.ntp.org:123";
+    let cf_ntp = "time.cloudflare.com:123";
+    let response = match broker_ntp::request(cf_ntp) {
+        Ok(res) => res,
+        Err(_) => broker_ntp::request(pool_ntp).unwrap()
+    };
+    let timestamp = response.transmit_timestamp;
+    broker_ntp::unix_time::Instant::from(timestamp).secs()
+}
+
+// cancel a future event or retract a published event and notify subscribers on the bus
+fn cancel(tree: &dyn Store, event_id: String, user_id: String, retract: bool, tx: Arc<Mutex<Bus<Event>>>) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+
+    let mut json = match tree.get_event(&event_id) {
+        Some(evt) => evt,
+        None => return (StatusCode::BAD_REQUEST, json!({"error": "event not found"}).to_string())
+    };
+    if json.tenant_id != user.tenant_id {
+        return (StatusCode::BAD_REQUEST, json!({"error": "trying to cancel event of wrong tenant"}).to_string())
+    }
+
+    // cancelling is idempotent
+    if json.cancelled {
+        return (StatusCode::OK, json!({"event": json}).to_string())
+    }
+    if json.published && !retract {
+        return (StatusCode::BAD_REQUEST, json!({"error": "event already published - retract it instead"}).to_string())
+    }
+    if !json.published && retract {
+        return (StatusCode::BAD_REQUEST, json!({"error": "event not published yet - cancel it instead"}).to_string())
+    }
+
+    let j = json.clone();
+    json.cancelled = true;
+    if tree.swap_event(&j, &json) {
+        if json.published {
+            refresh_state(tree, json.tenant_id, &json.event, json.collection_id);
+        } else {
+            let _ = tree.remove(pending_key(&json).as_bytes());
+        }
+        if let Some(key) = expiry_key(&json) {
+            let _ = tree.remove(key.as_bytes());
+        }
+        persist(tree);
+        tx.lock().unwrap().broadcast(json.clone());
+        return (StatusCode::OK, json!({"event": json}).to_string())
+    }
+    (StatusCode::BAD_REQUEST, json!({"error": "event was published while cancelling - retract it instead"}).to_string())
+}
+
+// encode a cursor from the timestamp and id of an event
+fn encode_cursor(evt: &Event) -> String {
+    base64_encode(&format!("{}_{}", evt.timestamp, evt.id))
+}
+
+// decode a cursor to the timestamp and id of an event
+fn decode_cursor(cursor: &str) -> Result<(i64, uuid::Uuid), String> {
+    let err = "invalid cursor".to_owned();
+    let bytes = base64_decode(cursor).map_err(|_| err.clone())?;
+    let decoded = std::str::from_utf8(&bytes).map_err(|_| err.clone())?;
+    let mut parts = decoded.splitn(2, "_");
+    let timestamp = parts.next().unwrap().parse::<i64>().map_err(|_| err.clone())?;
+    let id = parts.next().unwrap_or("").parse::<uuid::Uuid>().map_err(|_| err.clone())?;
+    Ok((timestamp, id))
+}
+
+// filter, sort and limit events by the page query - returns the cursor of the next page if there is one
+fn paginate(mut events: Vec<Event>, page: &Page) -> Result<(Vec<Event>, Option<String>), String> {
+
+    let desc = match page.order.as_deref() {
+        None | Some("asc") => false,
+        Some("desc") => true,
+        Some(_) => return Err("order must be asc or desc".to_owned())
+    };
+    let cursor = match &page.cursor {
+        Some(c) => Some(decode_cursor(c)?),
+        None => None
+    };
+    let include_cancelled = page.include_cancelled.unwrap_or(true);
+
+    events.retain(|evt| {
+        page.from.map_or(true, |from| evt.timestamp >= from)
+            && page.to.map_or(true, |to| evt.timestamp <= to)
+            && page.event.as_ref().map_or(true, |event| &evt.event == event)
+            && (include_cancelled || !evt.cancelled)
+    });
+
+    events.sort_by(|a, b| (a.timestamp, a.id).cmp(&(b.timestamp, b.id)));
+    if desc {
+        events.reverse();
+    }
+
+    // only keep events after the cursor in the sort direction
+    if let Some(position) = cursor {
+        events.retain(|evt| {
+            if desc {
+                (evt.timestamp, evt.id) < position
+            } else {
+                (evt.timestamp, evt.id) > position
+            }
+        });
+    }
+
+    let mut next_cursor = None;
+    if let Some(limit) = page.limit {
+        if events.len() > limit {
+            events.truncate(limit);
+            next_cursor = events.last().map(encode_cursor);
+        }
+    }
+    Ok((events, next_cursor))
+}
+
+// display user collection of events
+fn user_collection(tree: &dyn Store, id: String, page: Page) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+
+    let mut info: Vec<Event> = tree.collection_events(user.collection_id);
+
+    info.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+    let owned: Vec<Event> = tree.user_events(user.id);
+
+    let (owned, next_cursor) = match paginate(owned, &page) {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::BAD_REQUEST, json!({"error": e}).to_string())
+    };
 
-    records.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    let c = UserCollection{info: info, events: owned, next_cursor: next_cursor};
+    let data : String = serde_json::to_string(&c).unwrap();
+    (StatusCode::OK, data)
+}
+
+// display collection of events based on collection_id
+fn collection(tree: &dyn Store, collection_id: String, user_id: String, page: Page) -> (StatusCode, String) {
+ 
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+
+    let records: Vec<Event> = match collection_id.parse::<uuid::Uuid>() {
+        Ok(collection_id) => tree.collection_events(collection_id).into_iter().filter(|evt| evt.tenant_id == user.tenant_id).collect(),
+        Err(_) => Vec::new()
+    };
 
-    let c = Collection{events: records};
+    let (records, next_cursor) = match paginate(records, &page) {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::BAD_REQUEST, json!({"error": e}).to_string())
+    };
+
+    let c = Collection{events: records, next_cursor: next_cursor};
     let data : String = serde_json::to_string(&c).unwrap();
-    data
+    (StatusCode::OK, data)
 }
 
 // create a user
-fn user_create(tree: sled::Db, user_form: UserForm) -> (bool, String) {
+fn user_create(tree: &dyn Store, user_form: UserForm) -> (bool, String) {
  
-    let records : HashMap<String, String> = tree.iter().into_iter().filter(|x| {
-        let p = x.as_ref().unwrap();
-        let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-        if k.contains("_u_") {
-            let user : User = serde_json::from_str(&v).unwrap();
-            if user.username == user_form.username {
-                return true
-            } else {
-                return false
-            }
+    let records = tree.users_named(&user_form.username);
+
+    // the tenant must exist and have room for another user
+    let tenant = match tree.get_tenant(user_form.tenant_id) {
+        Some(tenant) => tenant,
+        None => return (false, json!({"error": "unknown tenant"}).to_string())
+    };
+    if let Some(max_users) = tenant.settings.limits.and_then(|limits| limits.max_users) {
+        if tree.tenant_users(tenant.id).len() >= max_users {
+            return (false, json!({"error": "tenant has reached its maximum number of users"}).to_string())
         }
-        return false
-    }).map(|x| {
-        let p = x.unwrap();
-        let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-        (k, v)
-    }).collect();
+    }
 
     if records.len() > 0 {
         let j = json!({"error": "username already taken"}).to_string();
@@ -383,42 +992,20 @@ fn user_create(tree: sled::Db, user_form: UserForm) -> (bool, String) {
         let new_user = User{id: uuid, username: user_form.clone().username, password: hashed, collection_id: user_form.clone().collection_id, tenant_id: user_form.clone().tenant_id };
         
         let _ = tree.compare_and_swap(versioned.as_bytes(), None as Option<&[u8]>, Some(serde_json::to_string(&new_user).unwrap().as_bytes())); 
-        let _ = tree.flush();
+        persist(tree);
         let j = json!({"id": uuid.to_string()}).to_string();
         return (true, j)
     }
 }
 
 // login with user creds
-fn login(tree: sled::Db, login: Login, config: Config) -> (bool, String) {
+fn login(tree: &dyn Store, login: Login, config: Config) -> (bool, String) {
 
     let now = get_ntp_time();
     let expi = now + config.expiry;
     let expiry = expi as usize;
 
-    let records : HashMap<String, String> = tree.iter().into_iter().filter(|x| {
-        let p = x.as_ref().unwrap();
-        let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-        if k.contains(&"_u_") {
-            let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-            let user : User = serde_json::from_str(&v).unwrap();
-            if user.username == login.username {
-                return true
-            } else {
-                return false
-            }
-        } else {
-            return false
-        }
-    }).map(|x| {
-        let p = x.unwrap();
-        let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-        (k, v)
-    }).collect();
-
-    for (_k, v) in records {
-        let user : User = serde_json::from_str(&v).unwrap();
+    for user in tree.users_named(&login.username) {
         let verified = verify(login.password, &user.password).unwrap();
         if verified {
             let my_claims = Claims{company: "".to_owned(), sub: user.id.to_string(), exp: expiry};
@@ -432,6 +1019,17 @@ fn login(tree: sled::Db, login: Login, config: Config) -> (bool, String) {
     (false, "".to_owned())
 }
 
+// make writes durable based on the durability mode - flush on every write (flush), flush writes together every group-commit-ms (group), or leave it to the periodic flush of sled (background)
+fn persist(tree: &dyn Store) {
+    match config().durability.as_str() {
+        "group" => DIRTY.store(true, Ordering::SeqCst),
+        "background" => {},
+        _ => {
+            let _ = tree.flush();
+        }
+    }
+}
+
 // config based on sane local dev defaults (uses double dashes for flags)
 fn config() -> Config {
  
@@ -442,7 +1040,19 @@ fn config() -> Config {
     let mut secret = "secret".to_owned();
     let mut key_path = "./broker.rsa".to_owned();
     let mut cert_path = "./broker.pem".to_owned();
-    let _ : Vec<String> = go_flag::parse(|flags| {
+    let mut idempotency_window : i64 = 86400;
+    let mut durability = "flush".to_owned();
+    let mut group_commit_ms : u64 = 50;
+    let mut admin_secret = "".to_owned();
+    let mut dry_run = false;
+    let mut encoding = "json".to_owned();
+    let mut store = "sled".to_owned();
+    let mut retention_interval : u64 = 3600;
+    let mut archive_path = "./tmp/broker_archive".to_owned();
+    let mut webhook_attempts : u32 = 8;
+    let mut webhook_backoff : i64 = 2;
+    let mut webhook_allow_private = false;
+    let command : Vec<String> = go_flag::parse(|flags| {
         flags.add_flag("port", &mut port);
         flags.add_flag("origin", &mut origin);
         flags.add_flag("expiry", &mut expiry);
@@ -450,6 +1060,18 @@ fn config() -> Config {
         flags.add_flag("connection", &mut connection);
         flags.add_flag("key-path", &mut key_path);
         flags.add_flag("cert-path", &mut cert_path);
+        flags.add_flag("idempotency-window", &mut idempotency_window);
+        flags.add_flag("durability", &mut durability);
+        flags.add_flag("group-commit-ms", &mut group_commit_ms);
+        flags.add_flag("admin-secret", &mut admin_secret);
+        flags.add_flag("dry-run", &mut dry_run);
+        flags.add_flag("encoding", &mut encoding);
+        flags.add_flag("store", &mut store);
+        flags.add_flag("retention-interval", &mut retention_interval);
+        flags.add_flag("archive-path", &mut archive_path);
+        flags.add_flag("webhook-attempts", &mut webhook_attempts);
+        flags.add_flag("webhook-backoff", &mut webhook_backoff);
+        flags.add_flag("webhook-allow-private", &mut webhook_allow_private);
     });
 
     let save_path = match envy::from_env::<Cfg>() {
@@ -457,7 +1079,7 @@ fn config() -> Config {
         Err(_) => "./tmp/broker_data".to_owned()
     };
 
-    Config{port: port, secret: secret, origin: origin, save_path: save_path, expiry: expiry, connection: connection, key_path: key_path, cert_path: cert_path}
+    Config{port: port, secret: secret, origin: origin, save_path: save_path, expiry: expiry, connection: connection, key_path: key_path, cert_path: cert_path, idempotency_window: idempotency_window, durability: durability, group_commit_ms: group_commit_ms, admin_secret: admin_secret, dry_run: dry_run, encoding: encoding, store: store, retention_interval: retention_interval, archive_path: archive_path, webhook_attempts: webhook_attempts, webhook_backoff: webhook_backoff, webhook_allow_private: webhook_allow_private, command: command}
 }
 
 // verify the exp and key of the JWT or the HTTP Basic Username/Password
@@ -469,7 +1091,9 @@ fn jwt_verify(config: Config, token: String) -> JWT {
         let token = parts.next().unwrap();
         let _ = match decode::<Claims>(&token,  &DecodingKey::from_secret(config.secret.as_ref()), &Validation::default()) {
             Ok(c) => {
-                return JWT{check: true, claims: c.claims};
+                // the token of an erased user or of a user of a purged tenant is no longer valid
+                let check = store().get_user(&c.claims.sub).is_some();
+                return JWT{check: check, claims: c.claims};
             },
             Err(_) => {
                 return JWT{check: false, claims: Claims{company: "".to_owned(), exp: 0, sub: "".to_owned()}};
@@ -484,31 +1108,9 @@ fn jwt_verify(config: Config, token: String) -> JWT {
                         let mut username_password = d.split(":");
                         let username = username_password.next().unwrap();
                         let password = username_password.next().unwrap();
-                        let tree = TREE.get(&"tree".to_owned()).unwrap();
-
-                        let records : HashMap<String, String> = tree.iter().into_iter().filter(|x| {
-                            let p = x.as_ref().unwrap();
-                            let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-                            if k.contains(&"_u_") {
-                                let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-                                let user : User = serde_json::from_str(&v).unwrap();
-                                if user.username == username {
-                                    return true
-                                } else {
-                                    return false
-                                }
-                            } else {
-                                return false
-                            }
-                        }).map(|x| {
-                            let p = x.unwrap();
-                            let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-                            let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-                            (k, v)
-                        }).collect();
-                    
-                        for (_k, v) in records {
-                            let user : User = serde_json::from_str(&v).unwrap();
+                        let tree = store();
+
+                        for user in tree.users_named(username) {
                             let verified = verify(password, &user.password).unwrap();
                             if verified {
                                 return JWT{check: true, claims: Claims{company: "".to_owned(), exp: 0, sub: user.id.to_string()}};
@@ -530,244 +1132,2577 @@ fn jwt_verify(config: Config, token: String) -> JWT {
     JWT{check: false, claims: Claims{company: "".to_owned(), exp: 0, sub: "".to_owned()}}
 }
 
-// insert an event
-fn insert(tree: sled::Db, user_id: String, evt: EventForm) -> String {
+// insert an event - a replayed idempotency key returns the event inserted with it instead
+fn insert(tree: &dyn Store, user_id: String, evt: EventForm, idempotency_key: Option<String>, config: Config) -> (StatusCode, String) {
   
     // get user
-    let versioned = format!("_u_{}", user_id);
-    let g = tree.get(&versioned.as_bytes()).unwrap().unwrap();
-    let v = std::str::from_utf8(&g).unwrap().to_owned();
-    let user : User = serde_json::from_str(&v).unwrap();
-
-    // build event object
-    let id = Uuid::new_v4();
-    let j = Event{id: id, published: false, cancelled: false, data: evt.data, event: evt.event, timestamp: evt.timestamp, user_id: user.id, collection_id: evt.collection_id, tenant_id: evt.tenant_id};
-    let new_value_string = serde_json::to_string(&j).unwrap();
-    let new_value = new_value_string.as_bytes();
-    let versioned = format!("_v_{}", id.to_string());
-
-    // only write if form tenant_id and user tenant_id
-    if user.tenant_id == evt.tenant_id {
-        let _ = tree.compare_and_swap(versioned, None as Option<&[u8]>, Some(new_value.clone())); 
-        let _ = tree.flush();
-        return json!({"event": j}).to_string()
-    }
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
 
-    json!({"error": "trying to write to wrong tenant"}).to_string()
+    // build event object and only write if form tenant_id and user tenant_id
+    match build_event(tree, &user, evt) {
+        Ok(j) => {
+            let now = get_ntp_time();
+            // a replay is answered before the quota is checked so retrying an accepted request never gets a 429
+            if let Some(key) = &idempotency_key {
+                match claim_idempotency_key(tree, user.tenant_id, key, j.id, config.idempotency_window, now) {
+                    Ok(Some(original)) => return (StatusCode::OK, json!({"event": original}).to_string()),
+                    Ok(None) => {},
+                    Err(e) => return (StatusCode::CONFLICT, json!({"error": e}).to_string())
+                }
+            }
+            if let Err(e) = check_quota(tree, user.tenant_id, &vec![&j], now) {
+                if let Some(key) = &idempotency_key {
+                    release_idempotency_key(tree, user.tenant_id, key, j.id, now);
+                }
+                return (StatusCode::TOO_MANY_REQUESTS, json!({"error": e}).to_string())
+            }
+            let versioned = format!("_v_{}", j.id.to_string());
+            let encoded = encode_event(&j);
+            if tree.compare_and_swap(versioned.as_bytes(), None, Some(&encoded)).unwrap_or(false) {
+                tree.add_storage(user.tenant_id, encoded.len() as i64);
+            }
+            tree.schedule_event(&j);
+            persist(tree);
+            (StatusCode::OK, json!({"event": j}).to_string())
+        },
+        Err(e) => (StatusCode::OK, json!({"error": e}).to_string())
+    }
 }
 
-// create a sse event
-fn event_stream(rx: crossbeam::channel::Receiver<SSE>, allowed: bool) -> Result<impl ServerSentEvent, Infallible> {
+// key of an idempotency key of a tenant (idempotency keys are free-form so are base64ed)
+fn idempotency_key(tenant_id: uuid::Uuid, key: &str) -> String {
+    format!("_k_{}_{}", tenant_id, base64_encode(key))
+}
 
-    if allowed {
-        let sse = match rx.try_recv() {
-            Ok(sse) => sse,
-            Err(_) => {
-                let id = Uuid::new_v4();
-                let guid = id.to_string();
-                let polling = json!({"status": "polling"});
-                SSE{id: guid, event: "internal_status".to_owned(), data: polling.to_string(), retry: Duration::from_millis(5000), tenant_id: id}
+// claim an idempotency key for a new event - returns the original event if the key was already used within the window
+// a key claimed by a request that hasn't written its event yet is in progress for a grace period - after that (or when the event was deleted since) the key is free again
+fn claim_idempotency_key(tree: &dyn Store, tenant_id: uuid::Uuid, key: &str, event_id: uuid::Uuid, window: i64, now: i64) -> Result<Option<Event>, String> {
+    let versioned = idempotency_key(tenant_id, key);
+    let claim = serde_json::to_string(&IdempotencyKey{event_id: event_id, created_at: now}).unwrap();
+    loop {
+        let current = tree.get(versioned.as_bytes()).unwrap();
+        let used = current.as_ref().map(|g| {
+            let used : IdempotencyKey = serde_json::from_slice(&g).unwrap();
+            used
+        }).filter(|used| used.created_at + window > now);
+        if let Some(used) = used {
+            if let Some(original) = tree.get_event(&used.event_id.to_string()) {
+                return Ok(Some(original))
             }
-        };
-        Ok((
-            warp::sse::id(sse.id),
-            warp::sse::data(sse.data),
-            warp::sse::event(sse.event),
-            warp::sse::retry(sse.retry),
-        ))
-    } else {
-        let id = Uuid::new_v4();
-        let guid = id.to_string();
-        let denied = json!({"error": "denied"});
-        let sse = SSE{id: guid, event: "internal_status".to_owned(), data: denied.to_string(), retry: Duration::from_millis(5000), tenant_id: id};
-        Ok((
-            warp::sse::id(sse.id),
-            warp::sse::data(sse.data),
-            warp::sse::event(sse.event),
-            warp::sse::retry(sse.retry),
-        ))
+            if used.created_at + IDEMPOTENCY_GRACE > now {
+                return Err("a request with this idempotency key is still in progress".to_owned())
+            }
+        }
+        // claim the key atomically so concurrent replays insert only once
+        if tree.compare_and_swap(versioned.as_bytes(), current.as_deref(), Some(claim.as_bytes())).unwrap() {
+            return Ok(None)
+        }
     }
 }
 
-// main function
-pub async fn broker() {
-
-    // start logging
-    pretty_env_logger::init();
+// give up the claim of an idempotency key for an event that wasn't inserted
+fn release_idempotency_key(tree: &dyn Store, tenant_id: uuid::Uuid, key: &str, event_id: uuid::Uuid, now: i64) {
+    let claim = serde_json::to_string(&IdempotencyKey{event_id: event_id, created_at: now}).unwrap();
+    let _ = tree.compare_and_swap(idempotency_key(tenant_id, key).as_bytes(), Some(claim.as_bytes()), None);
+}
 
-    // user create route
-    let user_create_route = warp::post()
-        .and(warp::path("users"))
-        .and(warp::body::json())
-        .map(move |user: UserForm| {
-            let tree = TREE.get(&"tree".to_owned()).unwrap();
-            let (check, value) = user_create(tree.clone(), user.clone());
-            if check {
-                let reply = warp::reply::with_status(value, StatusCode::OK);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
-            } else {
-                let reply = warp::reply::with_status(value, StatusCode::BAD_REQUEST);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
-            }
-        });
-    
-    // auth check middleware
-    let auth_check = warp::header::<String>("authorization").map(|token| {
-        let configure = config();
-        jwt_verify(configure, token)
-    });
+// remove the idempotency keys older than the window
+fn prune_idempotency_keys(tree: &dyn Store, window: i64) {
+    let now = get_ntp_time();
+    for x in tree.scan_prefix(b"_k_") {
+        let p = x.unwrap();
+        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
+        let used : IdempotencyKey = serde_json::from_str(&v).unwrap();
+        if used.created_at + window <= now {
+            let _ = tree.compare_and_swap(&p.0, Some(&p.1), None);
+        }
+    }
+}
 
-    // login route
-    let login_route = warp::post()
-        .and(warp::path("login"))
-        .and(warp::body::json())
-        .map(move |login_form: Login| {
-            let configure = config();
-            let tree = TREE.get(&"tree".to_owned()).unwrap();
-            let (check, value) = login(tree.clone(), login_form.clone(), configure.clone());
-            if check {
-                let reply = warp::reply::with_status(value, StatusCode::OK);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
-            } else {
-                let reply = warp::reply::with_status(value, StatusCode::UNAUTHORIZED);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
-            }
-        });
+// build a new event of a user from a form
+fn build_event(tree: &dyn Store, user: &User, evt: EventForm) -> Result<Event, String> {
+    if user.tenant_id != evt.tenant_id {
+        return Err("trying to write to wrong tenant".to_owned())
+    }
+    if tree.get_tenant(evt.tenant_id).is_none() {
+        return Err("unknown tenant".to_owned())
+    }
+    let expires_at = match (evt.expires_at, evt.ttl) {
+        (Some(expires_at), _) => Some(expires_at),
+        (None, Some(ttl)) => Some(evt.timestamp + ttl),
+        (None, None) => None
+    };
+    if expires_at.map_or(false, |expires_at| expires_at <= evt.timestamp) {
+        return Err("event must expire after its timestamp".to_owned())
+    }
+    Ok(Event{id: Uuid::new_v4(), published: false, cancelled: false, data: evt.data, event: evt.event, timestamp: evt.timestamp, user_id: user.id, collection_id: evt.collection_id, tenant_id: evt.tenant_id, expires_at: expires_at, expired: false})
+}
 
-    // insert route
-    let insert_route = warp::post()
-        .and(warp::path("insert"))
-        .and(auth_check)
-        .and(warp::body::json())
-        .map(move |jwt: JWT, event_form: EventForm| {
-            if jwt.check {
-                let tree = TREE.get(&"tree".to_owned()).unwrap();
-                let record = insert(tree.clone(), jwt.claims.sub, event_form);
-                let reply = warp::reply::with_status(record, StatusCode::OK);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
-            } else {
-                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
-            }
-        });
+// insert a batch of events (a json array or newline delimited json) in one write - all or nothing if atomic
+fn insert_batch(tree: &dyn Store, user_id: String, body: &[u8], atomic: bool) -> (StatusCode, String) {
 
-    // create thread-safe broadcast bus
-    let mix_tx = Bus::new(100);
-    let tx = Arc::new(Mutex::new(mix_tx));
-    let tx2 = tx.clone();
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
 
-    // create tokio worker thread that will dispatch events to bus
-    let _ = tokio::spawn(async move {
-        loop {
-            // get events that have not been published or cancelled
-            let tree = TREE.get(&"tree".to_owned()).unwrap();
-            let vals : HashMap<String, Event> = tree.iter().into_iter().filter(|x| {
-                let p = x.as_ref().unwrap();
-                let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-                if k.contains("_v_") {
-                    let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-                    let evt : Event = serde_json::from_str(&v).unwrap();
-                    if !evt.published && !evt.cancelled {
-                        let now = get_ntp_time();
-                        if evt.timestamp <= now  {
-                            return true
-                        } else {
-                            return false
-                        }
-                    } else {
-                        return false
-                    }
-                } else {
-                    return false
-                }
-            }).map(|x| {
-                let p = x.as_ref().unwrap();
-                let k = std::str::from_utf8(&p.0).unwrap().to_owned();
-                let v = std::str::from_utf8(&p.1).unwrap().to_owned();
-                let evt : Event = serde_json::from_str(&v).unwrap();
-                let evt_cloned = evt.clone();
-                (k, evt_cloned)
-            }).collect();
-
-            // publish these filtered events to bus
-            for (k, v) in vals {
-                let old_json = v.clone();
-                let old_json_clone = old_json.clone();
-                let mut new_json = v.clone();
-                new_json.published = true;
-                let newest_json = new_json.clone();
-                let newer_json = newest_json.clone();
-                let tree_cloned = tree.clone();
-
-                let _ = tokio::spawn(async move {
-                    let _ = tree_cloned.compare_and_swap(k, Some(serde_json::to_string(&old_json_clone).unwrap().as_bytes()), Some(serde_json::to_string(&newest_json).unwrap().as_bytes())); 
-                    let _ = tree_cloned.flush();
-                }).await;
-                
-                tx2.lock().unwrap().broadcast(newer_json);
-            }
-        }  
-    });
-    
-    // create bus middleware
-    let with_sender = warp::any().map(move || tx.clone());
+    let body = match std::str::from_utf8(body) {
+        Ok(body) => body,
+        Err(_) => return (StatusCode::BAD_REQUEST, json!({"error": "body must be utf-8"}).to_string())
+    };
+    let items : Vec<Result<serde_json::Value, String>> = if body.trim_start().starts_with("[") {
+        match serde_json::from_str::<Vec<serde_json::Value>>(body) {
+            Ok(items) => items.into_iter().map(Ok).collect(),
+            Err(e) => return (StatusCode::BAD_REQUEST, json!({"error": e.to_string()}).to_string())
+        }
+    } else {
+        body.lines().filter(|line| line.trim().len() > 0).map(|line| serde_json::from_str(line).map_err(|e| e.to_string())).collect()
+    };
 
-    // sse route
-    let sse_route = warp::path("events")
-        .and(auth_check)
-        .and(with_sender)
-        .and(warp::path::param::<uuid::Uuid>())
-        .and(warp::get()).map(move |jwt: JWT, tx_main: Arc<Mutex<bus::Bus<Event>>>, tenant_id: uuid::Uuid| {
+    let built : Vec<Result<Event, String>> = items.into_iter().map(|item| {
+        let form : EventForm = serde_json::from_value(item?).map_err(|e| e.to_string())?;
+        build_event(tree, &user, form)
+    }).collect();
 
-        // create recv for bus (each sse instance must have its own)
-        let mut rx_main = tx_main.lock().unwrap().add_rx();
+    let invalid = built.iter().any(|evt| evt.is_err());
+    if atomic && invalid {
+        let results : Vec<serde_json::Value> = built.iter().map(|evt| match evt {
+            Ok(_) => json!({"error": "not inserted - batch has invalid events"}),
+            Err(e) => json!({"error": e})
+        }).collect();
+        return (StatusCode::BAD_REQUEST, json!({"results": results}).to_string())
+    }
 
-        // create local sse channel
-        let (tx, rx) = unbounded();
+    // the tenant must have quota for all the valid events
+    let valid : Vec<&Event> = built.iter().filter_map(|evt| evt.as_ref().ok()).collect();
+    let now = get_ntp_time();
+    if let Err(e) = check_quota(tree, user.tenant_id, &valid, now) {
+        return (StatusCode::TOO_MANY_REQUESTS, json!({"error": e}).to_string())
+    }
 
-        // loop through sse events to send on load of sse route
-        for event in get_events(tenant_id) {
-            let _ = tx.send(event);
+    // write the events with their queue and index entries in one batch
+    let mut batch = Batch::default();
+    let mut bytes = 0;
+    for evt in built.iter().filter_map(|evt| evt.as_ref().ok()) {
+        let id = evt.id.to_string();
+        let encoded = encode_event(evt);
+        bytes += encoded.len() as i64;
+        batch.insert(format!("_v_{}", id).as_bytes(), &encoded);
+        for key in tree.schedule_entries(evt) {
+            batch.insert(key.as_bytes(), id.as_bytes());
         }
+    }
+    if let Err(e) = tree.apply_batch(batch) {
+        return (StatusCode::BAD_REQUEST, json!({"error": e.to_string()}).to_string())
+    }
+    tree.add_storage(user.tenant_id, bytes);
+    persist(tree);
 
-        // every 100ms check the bus and if any messages send to local channel also check local channel and publish to stream (sse route)
-        let event_stream = interval(Duration::from_millis(100)).map(move |_| {
-            let evt = match rx_main.try_recv() {
-                Ok(evt) => {
-                    if tenant_id == evt.tenant_id {
-                        evt
-                    } else {
-                        let id = Uuid::new_v4();
-                        Event{id: id, published: false, cancelled: false, data: json!({"test": "test"}), event: "fake".to_owned(), timestamp: 123, user_id: id, collection_id: id, tenant_id: id}
-                    }
-                },
-                Err(_) => {
-                    let id = Uuid::new_v4();
-                    Event{id: id, published: false, cancelled: false, data: json!({"test": "test"}), event: "fake".to_owned(), timestamp: 123, user_id: id, collection_id: id, tenant_id: id}
-                }
-            };
-            for event in get_events(evt.tenant_id) {
-                let _ = tx.send(event);
-            }
-            event_stream(rx.clone(), jwt.check)
-        });
-        warp::sse::reply(event_stream)
-    });
+    let results : Vec<serde_json::Value> = built.iter().map(|evt| match evt {
+        Ok(evt) => json!({"event": evt}),
+        Err(e) => json!({"error": e})
+    }).collect();
+    (StatusCode::OK, json!({"results": results}).to_string())
+}
 
-    // cancel route
-    let cancel_route = warp::get()
-        .and(warp::path("cancel"))
-        .and(auth_check)
-        .and(warp::path::param::<String>())
-        .map(move |jwt: JWT, event_id: String| {
-            if jwt.check {
-                let tree = TREE.get(&"tree".to_owned()).unwrap();
-                let record = cancel(tree.clone(), event_id, jwt.claims.sub);
-                let reply = warp::reply::with_status(record, StatusCode::OK);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
+
+// reschedule a future event and optionally replace its data
+fn reschedule(tree: &dyn Store, event_id: String, user_id: String, form: RescheduleForm) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+
+    let old = match tree.get_event(&event_id) {
+        Some(evt) => evt,
+        None => return (StatusCode::BAD_REQUEST, json!({"error": "event not found"}).to_string())
+    };
+    if old.tenant_id != user.tenant_id {
+        return (StatusCode::BAD_REQUEST, json!({"error": "trying to reschedule event of wrong tenant"}).to_string())
+    }
+    if old.published || old.cancelled {
+        return (StatusCode::BAD_REQUEST, json!({"error": "only future events can be rescheduled"}).to_string())
+    }
+
+    // keep the time to live of expiring events
+    let mut json = old.clone();
+    json.timestamp = form.timestamp;
+    json.expires_at = old.expires_at.map(|expires_at| expires_at + form.timestamp - old.timestamp);
+    if let Some(data) = form.data {
+        json.data = data;
+        // the new data is held to the payload and storage quotas like an insert
+        let limits = tree.get_tenant(user.tenant_id).and_then(|tenant| tenant.settings.limits).unwrap_or_default();
+        if let Err(e) = check_storage_quota(tree, user.tenant_id, &limits, &vec![&json], encode_event(&old).len() as u64) {
+            return (StatusCode::TOO_MANY_REQUESTS, json!({"error": e}).to_string())
+        }
+    }
+
+    if tree.swap_event(&old, &json) {
+        // move the event in the pending and expiry queues and update its index entries
+        let _ = tree.remove(pending_key(&old).as_bytes());
+        if let Some(key) = expiry_key(&old) {
+            let _ = tree.remove(key.as_bytes());
+        }
+        for entry in tree.index_entries(&old) {
+            let _ = tree.remove(entry.as_bytes());
+        }
+        tree.schedule_event(&json);
+        persist(tree);
+        return (StatusCode::OK, json!({"event": json}).to_string())
+    }
+    (StatusCode::BAD_REQUEST, json!({"error": "event was published while rescheduling"}).to_string())
+}
+
+// key of a recurring event template of a tenant
+fn recurrence_key(tenant_id: uuid::Uuid, id: uuid::Uuid) -> String {
+    format!("_r_{}_{}", tenant_id, id)
+}
+
+// get a recurring event template of a tenant
+fn get_recurrence(tree: &dyn Store, tenant_id: uuid::Uuid, id: &str) -> Option<Recurrence> {
+    let key = format!("_r_{}_{}", tenant_id, id);
+    match tree.get(key.as_bytes()).unwrap() {
+        Some(g) => {
+            let v = std::str::from_utf8(&g).unwrap().to_owned();
+            Some(serde_json::from_str(&v).unwrap())
+        },
+        None => None
+    }
+}
+
+// save a recurring event template
+fn put_recurrence(tree: &dyn Store, recurrence: &Recurrence) {
+    let _ = tree.insert(recurrence_key(recurrence.tenant_id, recurrence.id).as_bytes(), serde_json::to_string(recurrence).unwrap().as_bytes());
+}
+
+// check the schedule of a recurring event template - the cron expression parses or the frequency is known and the time zone exists
+fn validate_schedule(cron: &Option<String>, frequency: &Option<String>, timezone: &str) -> Result<(), String> {
+    chrono_tz::Tz::from_str(timezone)?;
+    match (cron, frequency) {
+        (Some(cron), None) => cron::Schedule::from_str(cron).map(|_| ()).map_err(|e| format!("invalid cron: {}", e)),
+        (None, Some(frequency)) => match frequency.as_str() {
+            "minutely" | "hourly" | "daily" | "weekly" => Ok(()),
+            _ => Err("frequency must be minutely, hourly, daily or weekly".to_owned())
+        },
+        _ => Err("either cron or frequency is required".to_owned())
+    }
+}
+
+// get the time of the next occurrence of a recurring event template after the previous one (or the first) skipping occurrences before now
+fn next_occurrence(recurrence: &Recurrence, previous: Option<i64>, now: i64) -> Result<Option<i64>, String> {
+
+    if let Some(count) = recurrence.count {
+        if recurrence.occurrences >= count {
+            return Ok(None)
+        }
+    }
+    let tz = chrono_tz::Tz::from_str(&recurrence.timezone)?;
+
+    let next = match (&recurrence.cron, &recurrence.frequency) {
+        (Some(cron), _) => {
+            let schedule = cron::Schedule::from_str(cron).map_err(|e| format!("invalid cron: {}", e))?;
+            let after = std::cmp::max(previous.unwrap_or(recurrence.starts_at - 1), now - 1);
+            schedule.after(&tz.timestamp(after, 0)).next().map(|d| d.timestamp())
+        },
+        (None, Some(frequency)) => {
+            let mut next = previous.unwrap_or(recurrence.starts_at);
+            let mut step = previous.is_some();
+            // step in local time so daily and weekly occurrences keep their time of day across DST changes
+            while step || next < now {
+                let local = tz.timestamp(next, 0).naive_local();
+                let stepped = match frequency.as_str() {
+                    "minutely" => local + chrono::Duration::minutes(recurrence.interval),
+                    "hourly" => local + chrono::Duration::hours(recurrence.interval),
+                    "daily" => local + chrono::Duration::days(recurrence.interval),
+                    "weekly" => local + chrono::Duration::weeks(recurrence.interval),
+                    _ => return Err("frequency must be minutely, hourly, daily or weekly".to_owned())
+                };
+                next = match tz.from_local_datetime(&stepped).earliest() {
+                    Some(d) => d.timestamp(),
+                    // skipped by a DST gap so use the same instant an hour later
+                    None => tz.from_local_datetime(&(stepped + chrono::Duration::hours(1))).earliest().unwrap().timestamp()
+                };
+                step = false;
+            }
+            Some(next)
+        },
+        (None, None) => return Err("cron or frequency is required".to_owned())
+    };
+
+    Ok(match (next, recurrence.ends_at) {
+        (Some(next), Some(ends_at)) if next > ends_at => None,
+        (next, _) => next
+    })
+}
+
+// create the future event of the next occurrence of a recurring event template after the previous one (or the first)
+// an occurrence over the quotas of the tenant is a 429 like an insert - other errors are of the schedule
+fn materialize(tree: &dyn Store, recurrence: &mut Recurrence, previous: Option<i64>, now: i64) -> Result<(), (StatusCode, String)> {
+
+    recurrence.next_at = next_occurrence(recurrence, previous, now).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    recurrence.next_event_id = None;
+
+    if let Some(timestamp) = recurrence.next_at {
+        let id = Uuid::new_v4();
+        let evt = Event{id: id, published: false, cancelled: false, data: recurrence.data.clone(), event: recurrence.event.clone(), timestamp: timestamp, user_id: recurrence.user_id, collection_id: recurrence.collection_id, tenant_id: recurrence.tenant_id, expires_at: None, expired: false};
+        check_quota(tree, evt.tenant_id, &vec![&evt], now).map_err(|e| (StatusCode::TOO_MANY_REQUESTS, e))?;
+        let encoded = encode_event(&evt);
+        if tree.insert(format!("_v_{}", id).as_bytes(), &encoded).is_ok() {
+            tree.add_storage(evt.tenant_id, encoded.len() as i64);
+        }
+        tree.schedule_event(&evt);
+        let _ = tree.insert(format!("_o_{}", id).as_bytes(), recurrence_key(recurrence.tenant_id, recurrence.id).as_bytes());
+        recurrence.next_event_id = Some(id);
+    }
+    put_recurrence(tree, recurrence);
+    Ok(())
+}
+
+// after an occurrence is published create the event of the next occurrence of its recurring event template
+// the occurrence link is taken atomically so an occurrence is either advanced here or cancelled by cancel_occurrence, never both
+fn advance_recurrence(tree: &dyn Store, evt: &Event, now: i64) {
+    let link = format!("_o_{}", evt.id);
+    if let Some(g) = tree.get(link.as_bytes()).unwrap() {
+        if !tree.compare_and_swap(link.as_bytes(), Some(&g), None).unwrap_or(false) {
+            return
+        }
+        if let Some(v) = tree.get(&g).unwrap() {
+            let v = std::str::from_utf8(&v).unwrap().to_owned();
+            let mut recurrence : Recurrence = serde_json::from_str(&v).unwrap();
+            recurrence.occurrences += 1;
+            // a template that can't be advanced is stopped with the reason
+            if let Err((_, e)) = materialize(tree, &mut recurrence, Some(evt.timestamp), now) {
+                log::warn!("recurring event {}: {}", recurrence.id, e);
+                recurrence.next_at = None;
+                recurrence.next_event_id = None;
+                recurrence.error = Some(e);
+                put_recurrence(tree, &recurrence);
+            }
+        }
+    }
+}
+
+// cancel the pending event of the next occurrence of a recurring event template and notify subscribers on the bus - returns the template as it is after
+// when the publisher took the occurrence first it is left to advance the template and the occurrence it creates is cancelled instead
+fn cancel_occurrence(tree: &dyn Store, tenant_id: uuid::Uuid, id: &str, tx: &Arc<Mutex<Bus<Event>>>) -> Option<Recurrence> {
+    for _ in 0..100 {
+        let mut recurrence = get_recurrence(tree, tenant_id, id)?;
+        let event_id = match recurrence.next_event_id {
+            Some(event_id) => event_id,
+            None => return Some(recurrence)
+        };
+        let link = format!("_o_{}", event_id);
+        match tree.get(link.as_bytes()).unwrap() {
+            Some(g) => {
+                if !tree.compare_and_swap(link.as_bytes(), Some(&g), None).unwrap_or(false) {
+                    continue
+                }
+                if let Some(old) = tree.get_event(&event_id.to_string()) {
+                    let mut evt = old.clone();
+                    evt.cancelled = true;
+                    if !old.published && !old.cancelled && tree.swap_event(&old, &evt) {
+                        let _ = tree.remove(pending_key(&evt).as_bytes());
+                        if let Some(key) = expiry_key(&evt) {
+                            let _ = tree.remove(key.as_bytes());
+                        }
+                        tx.lock().unwrap().broadcast(evt);
+                    }
+                }
+                recurrence.next_event_id = None;
+                return Some(recurrence)
+            },
+            None => {
+                // wait for the publisher to save the template with its next occurrence
+                match tree.get_event(&event_id.to_string()) {
+                    Some(evt) if evt.published => std::thread::sleep(Duration::from_millis(10)),
+                    _ => {
+                        recurrence.next_event_id = None;
+                        return Some(recurrence)
+                    }
+                }
+            }
+        }
+    }
+    get_recurrence(tree, tenant_id, id)
+}
+
+// create a recurring event template and the event of its first occurrence
+fn recurrence_create(tree: &dyn Store, user_id: String, form: RecurrenceForm) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    if user.tenant_id != form.tenant_id {
+        return (StatusCode::BAD_REQUEST, json!({"error": "trying to write to wrong tenant"}).to_string())
+    }
+    if tree.get_tenant(form.tenant_id).is_none() {
+        return (StatusCode::BAD_REQUEST, json!({"error": "unknown tenant"}).to_string())
+    }
+    let timezone = form.timezone.unwrap_or("UTC".to_owned());
+    if let Err(e) = validate_schedule(&form.cron, &form.frequency, &timezone) {
+        return (StatusCode::BAD_REQUEST, json!({"error": e}).to_string())
+    }
+    let interval = form.interval.unwrap_or(1);
+    if interval < 1 {
+        return (StatusCode::BAD_REQUEST, json!({"error": "interval must be at least 1"}).to_string())
+    }
+
+    let now = get_ntp_time();
+    let mut recurrence = Recurrence{
+        id: Uuid::new_v4(),
+        user_id: user.id,
+        collection_id: form.collection_id,
+        tenant_id: form.tenant_id,
+        event: form.event,
+        data: form.data,
+        cron: form.cron,
+        frequency: form.frequency,
+        interval: interval,
+        timezone: timezone,
+        starts_at: form.starts_at.unwrap_or(now),
+        ends_at: form.ends_at,
+        count: form.count,
+        occurrences: 0,
+        next_at: None,
+        next_event_id: None,
+        paused: false,
+        error: None,
+    };
+
+    match materialize(tree, &mut recurrence, None, now) {
+        Ok(()) => {
+            persist(tree);
+            (StatusCode::OK, serde_json::to_string(&recurrence).unwrap())
+        },
+        Err((status, e)) => (status, json!({"error": e}).to_string())
+    }
+}
+
+// display the recurring event templates of the user's tenant
+fn recurrences(tree: &dyn Store, user_id: String) -> (StatusCode, String) {
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let recurring : Vec<Recurrence> = tree.scan_prefix(format!("_r_{}_", user.tenant_id).as_bytes()).map(|x| {
+        let p = x.unwrap();
+        let v = std::str::from_utf8(&p.1).unwrap().to_owned();
+        let recurrence : Recurrence = serde_json::from_str(&v).unwrap();
+        recurrence
+    }).collect();
+    (StatusCode::OK, serde_json::to_string(&Recurrences{recurring: recurring}).unwrap())
+}
+
+// pause, resume or delete a recurring event template of the user's tenant
+fn recurrence_update(tree: &dyn Store, user_id: String, id: String, action: &str, tx: Arc<Mutex<Bus<Event>>>) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let mut recurrence = match get_recurrence(tree, user.tenant_id, &id) {
+        Some(recurrence) => recurrence,
+        None => return (StatusCode::BAD_REQUEST, json!({"error": "recurring event not found"}).to_string())
+    };
+
+    match action {
+        "pause" => {
+            if !recurrence.paused {
+                recurrence = match cancel_occurrence(tree, user.tenant_id, &id, &tx) {
+                    Some(recurrence) => recurrence,
+                    None => return (StatusCode::BAD_REQUEST, json!({"error": "recurring event not found"}).to_string())
+                };
+                recurrence.paused = true;
+                put_recurrence(tree, &recurrence);
+            }
+        },
+        "resume" => {
+            if recurrence.paused {
+                recurrence.paused = false;
+                recurrence.error = None;
+                if let Err((status, e)) = materialize(tree, &mut recurrence, None, get_ntp_time()) {
+                    return (status, json!({"error": e}).to_string())
+                }
+            }
+        },
+        _ => {
+            if let Some(cancelled) = cancel_occurrence(tree, user.tenant_id, &id, &tx) {
+                recurrence = cancelled;
+            }
+            let _ = tree.remove(recurrence_key(recurrence.tenant_id, recurrence.id).as_bytes());
+        }
+    }
+    persist(tree);
+    (StatusCode::OK, serde_json::to_string(&recurrence).unwrap())
+}
+
+// set the column definition of an event name for the user's tenant (an empty list removes it)
+fn columns_set(tree: &dyn Store, user_id: String, form: ColumnForm) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let key = columns_key(user.tenant_id, &form.event);
+    let c = Columns{columns: form.columns};
+
+    if c.columns.len() > 0 {
+        let _ = tree.insert(key.as_bytes(), serde_json::to_string(&c).unwrap().as_bytes());
+    } else {
+        let _ = tree.remove(key.as_bytes());
+    }
+    persist(tree);
+    (StatusCode::OK, serde_json::to_string(&c).unwrap())
+}
+
+// display the column definition of an event name for the user's tenant
+fn columns(tree: &dyn Store, user_id: String, event: String) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let columns = match get_columns(tree, user.tenant_id, &event) {
+        Some(columns) => columns,
+        None => Vec::new()
+    };
+    (StatusCode::OK, serde_json::to_string(&Columns{columns: columns}).unwrap())
+}
+
+
+
+// get the value of a field of an event - event, timestamp and collection_id or a (dotted) data field optionally prefixed with data.
+fn field_value(evt: &Event, field: &str) -> Option<serde_json::Value> {
+    match field {
+        "event" => Some(json!(evt.event)),
+        "timestamp" => Some(json!(evt.timestamp)),
+        "collection_id" => Some(json!(evt.collection_id)),
+        _ => {
+            let path = field.strip_prefix("data.").unwrap_or(field);
+            evt.data.pointer(&format!("/{}", path.replace(".", "/"))).cloned()
+        }
+    }
+}
+
+// compare numbers with numbers and strings with strings
+fn compare_values(a: &serde_json::Value, b: &serde_json::Value) -> Option<std::cmp::Ordering> {
+    match (a, b) {
+        (serde_json::Value::Number(x), serde_json::Value::Number(y)) => x.as_f64().unwrap().partial_cmp(&y.as_f64().unwrap()),
+        (serde_json::Value::String(x), serde_json::Value::String(y)) => Some(x.cmp(y)),
+        _ => None
+    }
+}
+
+// numbers are equal by value (so 1 equals 1.0) and everything else structurally
+fn equal_values(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+    match compare_values(a, b) {
+        Some(ordering) => ordering == std::cmp::Ordering::Equal,
+        None => a == b
+    }
+}
+
+// check if an event matches a filter
+fn matches(evt: &Event, filter: &QueryFilter) -> bool {
+    match filter {
+        QueryFilter::And{and} => and.iter().all(|f| matches(evt, f)),
+        QueryFilter::Or{or} => or.iter().any(|f| matches(evt, f)),
+        QueryFilter::Predicate(p) => {
+            let value = field_value(evt, &p.field);
+            if let Some(exists) = p.exists {
+                if value.is_some() != exists {
+                    return false
+                }
+            }
+            let value = match value {
+                Some(value) => value,
+                None => return p.eq.is_none() && p.gt.is_none() && p.gte.is_none() && p.lt.is_none() && p.lte.is_none() && p.one_of.is_none()
+            };
+            let ordered = |bound: &Option<serde_json::Value>, accept: &dyn Fn(std::cmp::Ordering) -> bool| {
+                match bound {
+                    Some(b) => compare_values(&value, b).map_or(false, |o| accept(o)),
+                    None => true
+                }
+            };
+            p.eq.as_ref().map_or(true, |b| equal_values(&value, b))
+                && p.one_of.as_ref().map_or(true, |bs| bs.iter().any(|b| equal_values(&value, b)))
+                && ordered(&p.gt, &|o| o == std::cmp::Ordering::Greater)
+                && ordered(&p.gte, &|o| o != std::cmp::Ordering::Less)
+                && ordered(&p.lt, &|o| o == std::cmp::Ordering::Less)
+                && ordered(&p.lte, &|o| o != std::cmp::Ordering::Greater)
+        }
+    }
+}
+
+// key of the index declaration of a field of a tenant (fields and values are free-form so are base64ed)
+fn index_key(tenant_id: uuid::Uuid, field: &str) -> String {
+    format!("_n_{}_{}", tenant_id, base64_encode(field))
+}
+
+// normalize a value for index lookups so numbers are equal by value like in matches
+fn index_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64().unwrap().to_string(),
+        _ => value.to_string()
+    }
+}
+
+// prefix of the index entries of a field value of a tenant
+fn index_entry_prefix(tenant_id: uuid::Uuid, field: &str, value: &serde_json::Value) -> String {
+    format!("_x_{}_{}_{}_", tenant_id, base64_encode(field), base64_encode(&index_value(value)))
+}
+
+
+
+// find the candidate event ids of a filter from the indexes - None if the filter can't be answered by the indexes
+fn index_candidates(tree: &dyn Store, tenant_id: uuid::Uuid, indexes: &Vec<String>, filter: &QueryFilter) -> Option<HashSet<uuid::Uuid>> {
+    match filter {
+        QueryFilter::Predicate(p) => {
+            if !indexes.contains(&p.field) {
+                return None
+            }
+            let values = match (&p.eq, &p.one_of) {
+                (Some(eq), _) => vec![eq.clone()],
+                (None, Some(one_of)) => one_of.clone(),
+                (None, None) => return None
+            };
+            let mut ids = HashSet::new();
+            for value in values {
+                for x in tree.scan_prefix(index_entry_prefix(tenant_id, &p.field, &value).as_bytes()) {
+                    let p = x.unwrap();
+                    let v = std::str::from_utf8(&p.1).unwrap().to_owned();
+                    ids.insert(v.parse::<uuid::Uuid>().unwrap());
+                }
+            }
+            Some(ids)
+        },
+        QueryFilter::And{and} => {
+            and.iter().filter_map(|f| index_candidates(tree, tenant_id, indexes, f)).fold(None, |acc: Option<HashSet<uuid::Uuid>>, ids| {
+                match acc {
+                    Some(acc) => Some(acc.intersection(&ids).cloned().collect()),
+                    None => Some(ids)
+                }
+            })
+        },
+        QueryFilter::Or{or} => {
+            let mut ids = HashSet::new();
+            for f in or {
+                ids.extend(index_candidates(tree, tenant_id, indexes, f)?);
+            }
+            Some(ids)
+        }
+    }
+}
+
+// get the events of a tenant matching a filter using the indexes when possible
+fn filtered_events(tree: &dyn Store, tenant_id: uuid::Uuid, filter: &QueryFilter) -> Vec<Event> {
+    let indexes = tree.get_indexes(tenant_id);
+    let candidates : Vec<Event> = match index_candidates(tree, tenant_id, &indexes, filter) {
+        Some(ids) => ids.iter().filter_map(|id| tree.get_event(&id.to_string())).collect(),
+        None => tree.tenant_events(tenant_id)
+    };
+    candidates.into_iter().filter(|evt| matches(evt, filter)).collect()
+}
+
+// query events of the user's tenant by a filter
+fn query(tree: &dyn Store, user_id: String, form: QueryForm) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let records = filtered_events(tree, user.tenant_id, &form.filter);
+
+    let (records, next_cursor) = match paginate(records, &form.page) {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::BAD_REQUEST, json!({"error": e}).to_string())
+    };
+
+    let c = Collection{events: records, next_cursor: next_cursor};
+    let data : String = serde_json::to_string(&c).unwrap();
+    (StatusCode::OK, data)
+}
+
+// running totals of a metric
+#[derive(Default)]
+struct Accumulator {
+    count: u64,
+    numbers: u64,
+    sum: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+// aggregate events of the user's tenant with metrics grouped by a field per time bucket
+fn aggregate(tree: &dyn Store, user_id: String, form: AggregateForm) -> (StatusCode, String) {
+
+    let bucket_size : Option<i64> = match form.bucket.as_deref() {
+        None => None,
+        Some("hour") => Some(3600),
+        Some("day") => Some(86400),
+        Some(_) => return (StatusCode::BAD_REQUEST, json!({"error": "bucket must be hour or day"}).to_string())
+    };
+    for metric in &form.metrics {
+        match (metric.op.as_str(), &metric.field) {
+            ("count", _) => {},
+            ("sum", Some(_)) | ("avg", Some(_)) | ("min", Some(_)) | ("max", Some(_)) => {},
+            ("sum", None) | ("avg", None) | ("min", None) | ("max", None) => return (StatusCode::BAD_REQUEST, json!({"error": format!("{} needs a field", metric.op)}).to_string()),
+            _ => return (StatusCode::BAD_REQUEST, json!({"error": "op must be count, sum, avg, min or max"}).to_string())
+        }
+    }
+    let metrics = if form.metrics.len() > 0 {
+        form.metrics.clone()
+    } else {
+        vec![Metric{op: "count".to_owned(), field: None, name: None}]
+    };
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let records = match &form.filter {
+        Some(filter) => filtered_events(tree, user.tenant_id, filter),
+        None => tree.tenant_events(user.tenant_id)
+    };
+    let include_cancelled = form.include_cancelled.unwrap_or(false);
+
+    // accumulate each metric per bucket and group (keyed by the json string of the group value)
+    let mut groups : std::collections::BTreeMap<(Option<i64>, String), (serde_json::Value, Vec<Accumulator>)> = std::collections::BTreeMap::new();
+    for evt in records {
+        if form.from.map_or(false, |from| evt.timestamp < from) || form.to.map_or(false, |to| evt.timestamp > to) || (evt.cancelled && !include_cancelled) {
+            continue
+        }
+        let bucket = bucket_size.map(|size| evt.timestamp - evt.timestamp.rem_euclid(size));
+        let group = match &form.group_by {
+            Some(field) => field_value(&evt, field).unwrap_or(serde_json::Value::Null),
+            None => serde_json::Value::Null
+        };
+        let entry = groups.entry((bucket, group.to_string())).or_insert_with(|| {
+            (group.clone(), metrics.iter().map(|_| Accumulator::default()).collect())
+        });
+        for (metric, acc) in metrics.iter().zip(entry.1.iter_mut()) {
+            let value = match &metric.field {
+                Some(field) => match field_value(&evt, field) {
+                    Some(value) => value,
+                    None => continue
+                },
+                None => serde_json::Value::Null
+            };
+            acc.count += 1;
+            if let Some(n) = value.as_f64() {
+                acc.numbers += 1;
+                acc.sum += n;
+                acc.min = Some(acc.min.map_or(n, |min| min.min(n)));
+                acc.max = Some(acc.max.map_or(n, |max| max.max(n)));
+            }
+        }
+    }
+
+    let results : Vec<Aggregate> = groups.into_iter().map(|((bucket, _), (group, accs))| {
+        let mut values = serde_json::Map::new();
+        for (metric, acc) in metrics.iter().zip(accs) {
+            let name = match (&metric.name, &metric.field) {
+                (Some(name), _) => name.clone(),
+                (None, Some(field)) => format!("{}({})", metric.op, field),
+                (None, None) => metric.op.clone()
+            };
+            let value = match metric.op.as_str() {
+                "count" => json!(acc.count),
+                "sum" => json!(acc.sum),
+                "avg" => if acc.numbers > 0 { json!(acc.sum / acc.numbers as f64) } else { serde_json::Value::Null },
+                "min" => json!(acc.min),
+                _ => json!(acc.max)
+            };
+            values.insert(name, value);
+        }
+        Aggregate{bucket: bucket, group: group, metrics: values}
+    }).collect();
+
+    (StatusCode::OK, serde_json::to_string(&Aggregates{results: results}).unwrap())
+}
+
+// declare an index on a field for the user's tenant and index the existing events
+fn index_create(tree: &dyn Store, user_id: String, form: IndexForm) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let _ = tree.insert(index_key(user.tenant_id, &form.field).as_bytes(), serde_json::to_string(&form).unwrap().as_bytes());
+
+    for evt in tree.tenant_events(user.tenant_id) {
+        if let Some(value) = field_value(&evt, &form.field) {
+            let entry = format!("{}{}", index_entry_prefix(user.tenant_id, &form.field, &value), evt.id);
+            let _ = tree.insert(entry.as_bytes(), evt.id.to_string().as_bytes());
+        }
+    }
+    persist(tree);
+    (StatusCode::OK, serde_json::to_string(&Indexes{indexes: tree.get_indexes(user.tenant_id)}).unwrap())
+}
+
+// remove an index on a field for the user's tenant and its entries
+fn index_remove(tree: &dyn Store, user_id: String, field: String) -> (StatusCode, String) {
+
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let _ = tree.remove(index_key(user.tenant_id, &field).as_bytes());
+
+    let prefix = format!("_x_{}_{}_", user.tenant_id, base64_encode(&field));
+    for x in tree.scan_prefix(prefix.as_bytes()) {
+        let p = x.unwrap();
+        let _ = tree.remove(&p.0);
+    }
+    persist(tree);
+    (StatusCode::OK, serde_json::to_string(&Indexes{indexes: tree.get_indexes(user.tenant_id)}).unwrap())
+}
+
+// display the indexed fields of the user's tenant
+fn indexes(tree: &dyn Store, user_id: String) -> (StatusCode, String) {
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    (StatusCode::OK, serde_json::to_string(&Indexes{indexes: tree.get_indexes(user.tenant_id)}).unwrap())
+}
+
+// key of a tenant
+fn tenant_key(tenant_id: uuid::Uuid) -> String {
+    format!("_t_{}", tenant_id)
+}
+
+fn put_tenant(tree: &dyn Store, tenant: &Tenant) {
+    let _ = tree.insert(tenant_key(tenant.id).as_bytes(), serde_json::to_string(tenant).unwrap().as_bytes());
+}
+
+// check the settings of a tenant
+fn validate_settings(settings: &TenantSettings) -> Result<(), String> {
+    if let Some(policy) = &settings.retention {
+        validate_retention(policy)?;
+    }
+    if let Some(limits) = &settings.limits {
+        if limits.max_users == Some(0) || limits.events_per_day == Some(0) || limits.storage_bytes == Some(0) || limits.max_payload_bytes == Some(0) || limits.max_subscribers == Some(0) {
+            return Err("limits must be greater than 0".to_owned())
+        }
+    }
+    Ok(())
+}
+
+// create a tenant - with a new id unless one is given for a tenant already used by clients
+fn tenant_create(tree: &dyn Store, form: TenantForm) -> (bool, String) {
+    let settings = form.settings.unwrap_or_default();
+    if let Err(e) = validate_settings(&settings) {
+        return (false, json!({"error": e}).to_string())
+    }
+    let tenant = Tenant{id: form.id.unwrap_or_else(Uuid::new_v4), name: form.name, settings: settings};
+    let purge : Option<Purge> = tree.get(purge_key(tenant.id).as_bytes()).unwrap().map(|g| serde_json::from_slice(&g).unwrap());
+    if purge.map_or(false, |purge| purge.status == "running") {
+        return (false, json!({"error": "tenant is being purged"}).to_string())
+    }
+    let created = tree.compare_and_swap(tenant_key(tenant.id).as_bytes(), None, Some(serde_json::to_string(&tenant).unwrap().as_bytes())).unwrap_or(false);
+    if !created {
+        return (false, json!({"error": "tenant already exists"}).to_string())
+    }
+    // the event streams of a purged tenant created again stay open
+    PURGING.lock().unwrap().remove(&tenant.id);
+    persist(tree);
+    (true, serde_json::to_string(&tenant).unwrap())
+}
+
+// display all tenants
+fn tenants(tree: &dyn Store) -> String {
+    serde_json::to_string(&Tenants{tenants: tree.tenants()}).unwrap()
+}
+
+// display a tenant
+fn tenant(tree: &dyn Store, tenant_id: uuid::Uuid) -> (bool, String) {
+    match tree.get_tenant(tenant_id) {
+        Some(tenant) => (true, serde_json::to_string(&tenant).unwrap()),
+        None => (false, json!({"error": "unknown tenant"}).to_string())
+    }
+}
+
+// rename a tenant or replace its settings
+fn tenant_update(tree: &dyn Store, tenant_id: uuid::Uuid, form: TenantUpdateForm) -> (bool, String) {
+    let mut tenant = match tree.get_tenant(tenant_id) {
+        Some(tenant) => tenant,
+        None => return (false, json!({"error": "unknown tenant"}).to_string())
+    };
+    if let Some(name) = form.name {
+        tenant.name = name;
+    }
+    if let Some(settings) = form.settings {
+        if let Err(e) = validate_settings(&settings) {
+            return (false, json!({"error": e}).to_string())
+        }
+        tenant.settings = settings;
+    }
+    put_tenant(tree, &tenant);
+    persist(tree);
+    (true, serde_json::to_string(&tenant).unwrap())
+}
+
+// remove a tenant without users or events
+fn tenant_remove(tree: &dyn Store, tenant_id: uuid::Uuid) -> (bool, String) {
+    let tenant = match tree.get_tenant(tenant_id) {
+        Some(tenant) => tenant,
+        None => return (false, json!({"error": "unknown tenant"}).to_string())
+    };
+    if tree.tenant_users(tenant_id).len() > 0 || tree.tenant_events(tenant_id).len() > 0 {
+        return (false, json!({"error": "tenant still has users or events"}).to_string())
+    }
+    let _ = tree.remove(tenant_key(tenant_id).as_bytes());
+    persist(tree);
+    (true, serde_json::to_string(&tenant).unwrap())
+}
+
+// the recurrences created by a user
+fn user_recurrences(tree: &dyn Store, user: &User) -> Vec<Recurrence> {
+    tree.scan_prefix(format!("_r_{}_", user.tenant_id).as_bytes()).map(|x| {
+        let p = x.unwrap();
+        let recurrence : Recurrence = serde_json::from_slice(&p.1).unwrap();
+        recurrence
+    }).filter(|recurrence| recurrence.user_id == user.id).collect()
+}
+
+// the queued deliveries and dead letters of the events a user authored with their keys
+fn user_deliveries(tree: &dyn Store, user: &User) -> Vec<(Vec<u8>, Delivery)> {
+    let dead_letters = format!("_y_{}_", user.tenant_id);
+    tree.scan_prefix(b"_d_").chain(tree.scan_prefix(dead_letters.as_bytes())).map(|x| {
+        let p = x.unwrap();
+        let delivery : Delivery = serde_json::from_slice(&p.1).unwrap();
+        (p.0, delivery)
+    }).filter(|(_, delivery)| delivery.tenant_id == user.tenant_id && delivery.event.user_id == user.id).collect()
+}
+
+// export everything a user has inserted with the user record without the password hash
+fn user_export(tree: &dyn Store, user_id: uuid::Uuid) -> (bool, String) {
+    let user = match tree.get_user(&user_id.to_string()) {
+        Some(user) => user,
+        None => return (false, json!({"error": "unknown user"}).to_string())
+    };
+    let mut events = tree.tenant_events(user.tenant_id);
+    events.sort_by(|a, b| (a.timestamp, a.id).cmp(&(b.timestamp, b.id)));
+    let export = UserExport{
+        user: UserInfo{id: user.id, username: user.username.clone(), collection_id: user.collection_id, tenant_id: user.tenant_id},
+        info: events.iter().filter(|evt| evt.collection_id == user.collection_id).cloned().collect(),
+        events: events.iter().filter(|evt| evt.user_id == user.id).cloned().collect(),
+        recurrences: user_recurrences(tree, &user),
+    };
+    (true, serde_json::to_string(&export).unwrap())
+}
+
+// erase a user and its recurrences - the events the user authored are deleted or anonymized (user_id replaced by the nil uuid)
+fn user_erase(tree: &dyn Store, user_id: uuid::Uuid, query: ErasureQuery, tx: Arc<Mutex<Bus<Event>>>) -> (bool, String) {
+    let mode = query.events.unwrap_or_default();
+    if mode != "delete" && mode != "anonymize" {
+        return (false, json!({"error": "events must be delete or anonymize"}).to_string())
+    }
+    let user = match tree.get_user(&user_id.to_string()) {
+        Some(user) => user,
+        None => return (false, json!({"error": "unknown user"}).to_string())
+    };
+
+    // stop the user's recurrences and cancel their pending occurrences before touching the events
+    for recurrence in user_recurrences(tree, &user) {
+        let _ = cancel_occurrence(tree, recurrence.tenant_id, &recurrence.id.to_string(), &tx);
+        let _ = tree.remove(recurrence_key(recurrence.tenant_id, recurrence.id).as_bytes());
+    }
+
+    let authored : Vec<Event> = tree.tenant_events(user.tenant_id).into_iter().filter(|evt| evt.user_id == user.id).collect();
+    if mode == "delete" {
+        if let Err(e) = prune_events(tree, user.tenant_id, &authored) {
+            return (false, json!({"error": e}).to_string())
+        }
+    } else {
+        let latest : HashSet<uuid::Uuid> = get_state(tree, user.tenant_id, None).iter().map(|evt| evt.id).collect();
+        for evt in authored.iter() {
+            // retry when the event changed (was published or cancelled) since it was read
+            let mut old = evt.clone();
+            loop {
+                let mut anonymized = old.clone();
+                anonymized.user_id = Uuid::nil();
+                if tree.swap_event(&old, &anonymized) {
+                    if latest.contains(&anonymized.id) {
+                        let _ = tree.insert(state_key(anonymized.tenant_id, &anonymized.event, anonymized.collection_id).as_bytes(), &encode_event(&anonymized));
+                    }
+                    break
+                }
+                old = match tree.get_event(&evt.id.to_string()) {
+                    Some(current) => current,
+                    None => break
+                };
+            }
+            let _ = tree.remove(format!("_o_{}", evt.id).as_bytes());
+        }
+    }
+
+    // the copies of the user's events in the webhook delivery queue and the dead letters go the same way
+    let mut batch = Batch::default();
+    for (key, mut delivery) in user_deliveries(tree, &user) {
+        if mode == "delete" {
+            batch.remove(&key);
+        } else {
+            delivery.event.user_id = Uuid::nil();
+            batch.insert(&key, serde_json::to_string(&delivery).unwrap().as_bytes());
+        }
+    }
+    if let Err(e) = tree.apply_batch(batch) {
+        return (false, json!({"error": e}).to_string())
+    }
+
+    let _ = tree.remove(format!("_u_{}", user.id).as_bytes());
+    persist(tree);
+    (true, json!({"erased": user.id, "events": authored.len(), "mode": mode}).to_string())
+}
+
+// key of a webhook of a tenant
+fn webhook_key(tenant_id: uuid::Uuid, id: uuid::Uuid) -> String {
+    format!("_w_{}_{}", tenant_id, id)
+}
+
+// key of a delivery in the delivery queue - ordered by when it is tried next
+fn delivery_key(delivery: &Delivery) -> String {
+    format!("_d_{:020}_{}", delivery.next_at, delivery.id)
+}
+
+// key of a dead letter of a tenant
+fn dead_letter_key(tenant_id: uuid::Uuid, id: uuid::Uuid) -> String {
+    format!("_y_{}_{}", tenant_id, id)
+}
+
+// get the webhooks of a tenant
+fn get_webhooks(tree: &dyn Store, tenant_id: uuid::Uuid) -> Vec<Webhook> {
+    tree.scan_prefix(format!("_w_{}_", tenant_id).as_bytes()).map(|x| {
+        let p = x.unwrap();
+        let webhook : Webhook = serde_json::from_slice(&p.1).unwrap();
+        webhook
+    }).collect()
+}
+
+// whether an address is loopback, private, link-local or unspecified - webhooks are not posted to these unless webhook-allow-private is set
+fn private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => private_address(IpAddr::V4(ip)),
+            None => ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80
+        }
+    }
+}
+
+// the host and port of a webhook url to resolve
+fn webhook_host(url: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err("url must be http or https".to_owned())
+    }
+    match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => Ok(format!("{}:{}", host, port)),
+        _ => Err("url must have a host".to_owned())
+    }
+}
+
+// check the addresses a webhook host resolves to
+fn check_addresses(addresses: Vec<SocketAddr>, allow_private: bool) -> Result<(), String> {
+    if addresses.is_empty() {
+        return Err("url host does not resolve".to_owned())
+    }
+    if !allow_private && addresses.iter().any(|address| private_address(address.ip())) {
+        return Err("url host is a loopback, private or link-local address".to_owned())
+    }
+    Ok(())
+}
+
+// add a webhook for the user's tenant - a secret is made up when none is given
+fn webhook_create(tree: &dyn Store, user_id: String, form: WebhookForm, config: Config) -> (StatusCode, String) {
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let host = match webhook_host(&form.url) {
+        Ok(host) => host,
+        Err(e) => return (StatusCode::BAD_REQUEST, json!({"error": e}).to_string())
+    };
+    let addresses = host.to_socket_addrs().map(|addresses| addresses.collect()).unwrap_or_default();
+    if let Err(e) = check_addresses(addresses, config.webhook_allow_private) {
+        return (StatusCode::BAD_REQUEST, json!({"error": e}).to_string())
+    }
+    let webhook = Webhook{
+        id: Uuid::new_v4(),
+        tenant_id: user.tenant_id,
+        url: form.url,
+        events: form.events.unwrap_or_default(),
+        secret: form.secret.unwrap_or_else(|| Uuid::new_v4().to_simple().to_string()),
+    };
+    let _ = tree.insert(webhook_key(webhook.tenant_id, webhook.id).as_bytes(), serde_json::to_string(&webhook).unwrap().as_bytes());
+    persist(tree);
+    (StatusCode::OK, serde_json::to_string(&webhook).unwrap())
+}
+
+// display the webhooks of the user's tenant
+fn webhooks(tree: &dyn Store, user_id: String) -> (StatusCode, String) {
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    (StatusCode::OK, serde_json::to_string(&Webhooks{webhooks: get_webhooks(tree, user.tenant_id)}).unwrap())
+}
+
+// remove a webhook of the user's tenant - its queued deliveries are dropped when they are due
+fn webhook_remove(tree: &dyn Store, user_id: String, id: String) -> (StatusCode, String) {
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let id = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, json!({"error": "unknown webhook"}).to_string())
+    };
+    let key = webhook_key(user.tenant_id, id);
+    if !tree.contains_key(key.as_bytes()).unwrap() {
+        return (StatusCode::BAD_REQUEST, json!({"error": "unknown webhook"}).to_string())
+    }
+    let _ = tree.remove(key.as_bytes());
+    persist(tree);
+    (StatusCode::OK, serde_json::to_string(&Webhooks{webhooks: get_webhooks(tree, user.tenant_id)}).unwrap())
+}
+
+// queue a delivery of a published event to every webhook of its tenant that wants it
+fn enqueue_deliveries(tree: &dyn Store, evt: &Event, now: i64) {
+    for webhook in get_webhooks(tree, evt.tenant_id) {
+        if !webhook.events.is_empty() && !webhook.events.contains(&evt.event) {
+            continue
+        }
+        let delivery = Delivery{id: Uuid::new_v4(), webhook_id: webhook.id, tenant_id: evt.tenant_id, event: evt.clone(), attempts: 0, next_at: now, last_error: None};
+        let _ = tree.insert(delivery_key(&delivery).as_bytes(), serde_json::to_string(&delivery).unwrap().as_bytes());
+    }
+}
+
+// the deliveries due by now with their webhooks - None for a removed webhook
+fn due_deliveries(tree: &dyn Store, now: i64) -> Vec<(Delivery, Option<Webhook>)> {
+    let mut due = Vec::new();
+    for x in tree.scan_prefix(b"_d_") {
+        let p = x.unwrap();
+        let delivery : Delivery = serde_json::from_slice(&p.1).unwrap();
+        if delivery.next_at > now {
+            break
+        }
+        let webhook = tree.get(webhook_key(delivery.tenant_id, delivery.webhook_id).as_bytes()).unwrap().map(|g| serde_json::from_slice(&g).unwrap());
+        due.push((delivery, webhook));
+    }
+    due
+}
+
+// the hex hmac-sha256 of a body with a secret
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+    mac.input(body.as_bytes());
+    mac.result().code().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// a resolver that answers every host with the addresses already checked - the connection goes to them without a second lookup a rebinding dns could answer differently
+#[derive(Clone)]
+struct PinnedResolver(Vec<IpAddr>);
+
+impl hyper::service::Service<hyper::client::connect::dns::Name> for PinnedResolver {
+    type Response = std::vec::IntoIter<IpAddr>;
+    type Error = std::io::Error;
+    type Future = futures::future::Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _: &mut std::task::Context<'_>) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: hyper::client::connect::dns::Name) -> Self::Future {
+        futures::future::ready(Ok(self.0.clone().into_iter()))
+    }
+}
+
+// post a delivery to its webhook - any 2xx response is a success
+async fn send_delivery(tls: &native_tls::TlsConnector, webhook: &Webhook, delivery: &Delivery, allow_private: bool) -> Result<(), String> {
+    // the host is resolved again as it may point somewhere else since the webhook was added
+    let host = webhook_host(&webhook.url)?;
+    let addresses : Vec<SocketAddr> = tokio::net::lookup_host(host).await.map(|addresses| addresses.collect()).unwrap_or_default();
+    check_addresses(addresses.clone(), allow_private)?;
+
+    // connect to the checked addresses - the url keeps the host for the Host header and tls
+    let mut http = hyper::client::HttpConnector::new_with_resolver(PinnedResolver(addresses.iter().map(|address| address.ip()).collect()));
+    http.enforce_http(false);
+    let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::from((http, tls.clone().into())));
+
+    let body = json!({"event": delivery.event}).to_string();
+    let req = hyper::Request::post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header("X-Broker-Delivery", delivery.id.to_string())
+        .header("X-Broker-Event", delivery.event.event.clone())
+        .header("X-Broker-Signature", format!("sha256={}", sign(&webhook.secret, &body)))
+        .body(hyper::Body::from(body))
+        .map_err(|e| e.to_string())?;
+    let res = match tokio::time::timeout(Duration::from_secs(10), client.request(req)).await {
+        Ok(res) => res.map_err(|e| e.to_string())?,
+        Err(_) => return Err("webhook timed out".to_owned())
+    };
+    if res.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("webhook responded {}", res.status()))
+    }
+}
+
+// take a tried delivery off the queue - a failed one is queued again with exponential backoff or becomes a dead letter after the last attempt
+fn record_delivery(tree: &dyn Store, mut delivery: Delivery, result: Result<(), String>, now: i64, config: &Config) {
+    let mut batch = Batch::default();
+    batch.remove(delivery_key(&delivery).as_bytes());
+    if let Err(e) = result {
+        delivery.attempts += 1;
+        delivery.last_error = Some(e);
+        if delivery.attempts >= config.webhook_attempts {
+            batch.insert(dead_letter_key(delivery.tenant_id, delivery.id).as_bytes(), serde_json::to_string(&delivery).unwrap().as_bytes());
+        } else {
+            // the wait doubles on every attempt up to a day
+            let backoff = config.webhook_backoff.saturating_mul(2i64.saturating_pow(delivery.attempts - 1));
+            delivery.next_at = now + std::cmp::min(backoff, 86400);
+            batch.insert(delivery_key(&delivery).as_bytes(), serde_json::to_string(&delivery).unwrap().as_bytes());
+        }
+    }
+    let _ = tree.apply_batch(batch);
+    persist(tree);
+}
+
+// display the dead letters of the user's tenant
+fn dead_letters(tree: &dyn Store, user_id: String) -> (StatusCode, String) {
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let letters : Vec<Delivery> = tree.scan_prefix(format!("_y_{}_", user.tenant_id).as_bytes()).map(|x| {
+        let p = x.unwrap();
+        let delivery : Delivery = serde_json::from_slice(&p.1).unwrap();
+        delivery
+    }).collect();
+    (StatusCode::OK, serde_json::to_string(&DeadLetters{dead_letters: letters}).unwrap())
+}
+
+// queue a dead letter of the user's tenant for delivery again (retry) or drop it
+fn dead_letter_update(tree: &dyn Store, user_id: String, id: String, retry: bool) -> (StatusCode, String) {
+    let user = match tree.get_user(&user_id) {
+        Some(user) => user,
+        None => return (StatusCode::UNAUTHORIZED, json!({"error": "unknown user"}).to_string())
+    };
+    let id = match Uuid::parse_str(&id) {
+        Ok(id) => id,
+        Err(_) => return (StatusCode::BAD_REQUEST, json!({"error": "unknown dead letter"}).to_string())
+    };
+    let key = dead_letter_key(user.tenant_id, id);
+    let mut delivery : Delivery = match tree.get(key.as_bytes()).unwrap() {
+        Some(g) => serde_json::from_slice(&g).unwrap(),
+        None => return (StatusCode::BAD_REQUEST, json!({"error": "unknown dead letter"}).to_string())
+    };
+    let mut batch = Batch::default();
+    batch.remove(key.as_bytes());
+    if retry {
+        delivery.attempts = 0;
+        delivery.next_at = get_ntp_time();
+        batch.insert(delivery_key(&delivery).as_bytes(), serde_json::to_string(&delivery).unwrap().as_bytes());
+    }
+    if let Err(e) = tree.apply_batch(batch) {
+        return (StatusCode::BAD_REQUEST, json!({"error": e}).to_string())
+    }
+    persist(tree);
+    (StatusCode::OK, serde_json::to_string(&delivery).unwrap())
+}
+
+// key of the purge of a tenant
+fn purge_key(tenant_id: uuid::Uuid) -> String {
+    format!("_j_{}", tenant_id)
+}
+
+fn put_purge(tree: &dyn Store, purge: &Purge) {
+    let _ = tree.insert(purge_key(purge.tenant_id).as_bytes(), serde_json::to_string(purge).unwrap().as_bytes());
+}
+
+fn purging(tenant_id: uuid::Uuid) -> bool {
+    PURGING.lock().unwrap().contains(&tenant_id)
+}
+
+// the keys of all the data of a tenant - users, events with their queue and recurrence entries and the records keyed by tenant
+fn tenant_keys(tree: &dyn Store, tenant_id: uuid::Uuid) -> Vec<Vec<u8>> {
+    let mut keys : Vec<Vec<u8>> = tree.tenant_users(tenant_id).iter().map(|user| format!("_u_{}", user.id).into_bytes()).collect();
+    keys.push(format!("_b_{}", tenant_id).into_bytes());
+    for evt in tree.tenant_events(tenant_id) {
+        keys.push(format!("_v_{}", evt.id).into_bytes());
+        keys.push(pending_key(&evt).into_bytes());
+        keys.extend(expiry_key(&evt).map(|key| key.into_bytes()));
+        keys.push(format!("_o_{}", evt.id).into_bytes());
+    }
+    for prefix in vec!["_c_", "_n_", "_x_", "_s_", "_r_", "_k_", "_q_", "_w_", "_y_"] {
+        for x in tree.scan_prefix(format!("{}{}_", prefix, tenant_id).as_bytes()) {
+            keys.push(x.unwrap().0);
+        }
+    }
+    // the delivery queue is ordered by when a delivery is tried next so it is scanned whole
+    for x in tree.scan_prefix(b"_d_") {
+        let p = x.unwrap();
+        let delivery : Delivery = serde_json::from_slice(&p.1).unwrap();
+        if delivery.tenant_id == tenant_id {
+            keys.push(p.0);
+        }
+    }
+    keys
+}
+
+// start purging a tenant - the tenant is removed at once so nothing new is written for it and its data is deleted by purge_tenant
+fn purge_start(tree: &dyn Store, tenant_id: uuid::Uuid) -> (bool, String) {
+    if tree.get_tenant(tenant_id).is_none() {
+        return (false, json!({"error": "unknown tenant"}).to_string())
+    }
+    if !PURGING.lock().unwrap().insert(tenant_id) {
+        return (false, json!({"error": "tenant is already being purged"}).to_string())
+    }
+    let purge = Purge{tenant_id: tenant_id, status: "running".to_owned(), total: 0, deleted: 0, error: None};
+    put_purge(tree, &purge);
+    let _ = tree.remove(tenant_key(tenant_id).as_bytes());
+    persist(tree);
+    (true, serde_json::to_string(&purge).unwrap())
+}
+
+// delete all the data of a tenant in batches recording the progress
+fn purge_tenant(tree: &dyn Store, tenant_id: uuid::Uuid) {
+    let keys = tenant_keys(tree, tenant_id);
+    let mut purge = Purge{tenant_id: tenant_id, status: "running".to_owned(), total: keys.len(), deleted: 0, error: None};
+    put_purge(tree, &purge);
+
+    for chunk in keys.chunks(500) {
+        let mut batch = Batch::default();
+        for key in chunk {
+            batch.remove(key);
+        }
+        if let Err(e) = tree.apply_batch(batch) {
+            purge.status = "failed".to_owned();
+            purge.error = Some(e);
+            break
+        }
+        purge.deleted += chunk.len();
+        put_purge(tree, &purge);
+        persist(tree);
+    }
+    if purge.error.is_none() {
+        purge.status = "done".to_owned();
+    }
+    put_purge(tree, &purge);
+    persist(tree);
+}
+
+// display the progress of the purge of a tenant
+fn purge_status(tree: &dyn Store, tenant_id: uuid::Uuid) -> (bool, String) {
+    match tree.get(purge_key(tenant_id).as_bytes()).unwrap() {
+        Some(g) => (true, std::str::from_utf8(&g).unwrap().to_owned()),
+        None => (false, json!({"error": "tenant has not been purged"}).to_string())
+    }
+}
+
+// resume the purges interrupted by a restart
+fn resume_purges(tree: &dyn Store) -> Vec<uuid::Uuid> {
+    let running : Vec<uuid::Uuid> = tree.scan_prefix(b"_j_").map(|x| {
+        let p = x.unwrap();
+        let purge : Purge = serde_json::from_slice(&p.1).unwrap();
+        purge
+    }).filter(|purge| purge.status == "running").map(|purge| purge.tenant_id).collect();
+    PURGING.lock().unwrap().extend(running.iter());
+    running
+}
+
+// key of the events inserted by a tenant on a day (days since the epoch)
+fn usage_key(tenant_id: uuid::Uuid, day: i64) -> String {
+    format!("_q_{}_{:010}", tenant_id, day)
+}
+
+// the events inserted by a tenant today
+fn events_today(tree: &dyn Store, tenant_id: uuid::Uuid, now: i64) -> u64 {
+    match tree.get(usage_key(tenant_id, now / 86400).as_bytes()).unwrap() {
+        Some(g) => std::str::from_utf8(&g).unwrap().parse::<u64>().unwrap(),
+        None => 0
+    }
+}
+
+// count events inserted by a tenant today - checked against the events per day and counted in one compare and swap so concurrent inserts can't go over it
+fn meter_events(tree: &dyn Store, tenant_id: uuid::Uuid, count: u64, events_per_day: Option<u64>, now: i64) -> Result<(), String> {
+    let key = usage_key(tenant_id, now / 86400);
+    let mut current = tree.get(key.as_bytes()).unwrap();
+    loop {
+        let used = current.as_ref().map_or(0, |g| std::str::from_utf8(&g).unwrap().parse::<u64>().unwrap());
+        if let Some(events_per_day) = events_per_day {
+            if used + count > events_per_day {
+                return Err(format!("tenant has reached its quota of {} events per day", events_per_day))
+            }
+        }
+        if tree.compare_and_swap(key.as_bytes(), current.as_deref(), Some((used + count).to_string().as_bytes())).unwrap() {
+            return Ok(())
+        }
+        current = tree.get(key.as_bytes()).unwrap();
+    }
+}
+
+// remove the daily event counts of the days before today
+fn prune_usage(tree: &dyn Store, now: i64) {
+    let today = now / 86400;
+    for x in tree.scan_prefix(b"_q_") {
+        let p = x.unwrap();
+        let k = std::str::from_utf8(&p.0).unwrap();
+        let day = k[k.len() - 10..].parse::<i64>().unwrap();
+        if day < today {
+            let _ = tree.remove(&p.0);
+        }
+    }
+}
+
+// check new events of a tenant against its quotas and count them as inserted today when they are within them
+fn check_quota(tree: &dyn Store, tenant_id: uuid::Uuid, events: &Vec<&Event>, now: i64) -> Result<(), String> {
+    let limits = tree.get_tenant(tenant_id).and_then(|tenant| tenant.settings.limits).unwrap_or_default();
+    check_storage_quota(tree, tenant_id, &limits, events, 0)?;
+    meter_events(tree, tenant_id, events.len() as u64, limits.events_per_day, now)
+}
+
+// check the payload and storage quotas of a tenant for events - replaced is the stored size of the events they replace (0 for new events)
+fn check_storage_quota(tree: &dyn Store, tenant_id: uuid::Uuid, limits: &TenantLimits, events: &Vec<&Event>, replaced: u64) -> Result<(), String> {
+    if let Some(max_payload_bytes) = limits.max_payload_bytes {
+        if events.iter().any(|evt| serde_json::to_string(&evt.data).unwrap().len() > max_payload_bytes) {
+            return Err(format!("event data is larger than the maximum payload of {} bytes", max_payload_bytes))
+        }
+    }
+    if let Some(storage_bytes) = limits.storage_bytes {
+        let adding : u64 = events.iter().map(|evt| encode_event(evt).len() as u64).sum();
+        if (tree.storage_used(tenant_id) + adding).saturating_sub(replaced) > storage_bytes {
+            return Err(format!("tenant has reached its storage quota of {} bytes", storage_bytes))
+        }
+    }
+    Ok(())
+}
+
+// display the consumption of a tenant with its limits
+fn tenant_usage(tree: &dyn Store, tenant_id: uuid::Uuid, now: i64) -> (bool, String) {
+    let tenant = match tree.get_tenant(tenant_id) {
+        Some(tenant) => tenant,
+        None => return (false, json!({"error": "unknown tenant"}).to_string())
+    };
+    let usage = Usage{
+        tenant_id: tenant_id,
+        users: tree.tenant_users(tenant_id).len(),
+        events: tree.tenant_events(tenant_id).len(),
+        events_today: events_today(tree, tenant_id, now),
+        storage_bytes: tree.storage_used(tenant_id),
+        subscribers: subscribers(tenant_id),
+        limits: tenant.settings.limits,
+    };
+    (true, serde_json::to_string(&usage).unwrap())
+}
+
+// whether a browser origin may open the event stream of a tenant - requests without an origin (not from a browser) are allowed
+fn origin_allowed(tree: &dyn Store, tenant_id: uuid::Uuid, origin: Option<&str>) -> bool {
+    let allowed = tree.get_tenant(tenant_id).and_then(|tenant| tenant.settings.origin);
+    match (allowed, origin) {
+        (Some(allowed), Some(origin)) => allowed == "*" || allowed == origin,
+        _ => true
+    }
+}
+
+// check the rules of a retention policy
+fn validate_retention(policy: &RetentionPolicy) -> Result<(), String> {
+    if policy.max_age.map_or(false, |max_age| max_age <= 0) || policy.max_events == Some(0) || policy.keep_latest == Some(0) {
+        return Err("max_age, max_events and keep_latest must be greater than 0".to_owned())
+    }
+    Ok(())
+}
+
+// set the retention policy of a tenant
+fn retention_set(tree: &dyn Store, tenant_id: uuid::Uuid, policy: RetentionPolicy) -> (bool, String) {
+    if let Err(e) = validate_retention(&policy) {
+        return (false, json!({"error": e}).to_string())
+    }
+    let mut tenant = match tree.get_tenant(tenant_id) {
+        Some(tenant) => tenant,
+        None => return (false, json!({"error": "unknown tenant"}).to_string())
+    };
+    tenant.settings.retention = Some(policy.clone());
+    put_tenant(tree, &tenant);
+    persist(tree);
+    (true, serde_json::to_string(&policy).unwrap())
+}
+
+// display the retention policy of a tenant - an empty policy keeps events forever
+fn retention(tree: &dyn Store, tenant_id: uuid::Uuid) -> (bool, String) {
+    match tree.get_tenant(tenant_id) {
+        Some(tenant) => (true, serde_json::to_string(&tenant.settings.retention.unwrap_or_default()).unwrap()),
+        None => (false, json!({"error": "unknown tenant"}).to_string())
+    }
+}
+
+// remove the retention policy of a tenant
+fn retention_remove(tree: &dyn Store, tenant_id: uuid::Uuid) -> (bool, String) {
+    let mut tenant = match tree.get_tenant(tenant_id) {
+        Some(tenant) => tenant,
+        None => return (false, json!({"error": "unknown tenant"}).to_string())
+    };
+    tenant.settings.retention = None;
+    put_tenant(tree, &tenant);
+    persist(tree);
+    (true, serde_json::to_string(&RetentionPolicy::default()).unwrap())
+}
+
+// the events of a tenant past a limit of its policy - only published, cancelled or expired events are pruned so the pending queue is left alone
+fn prunable(policy: &RetentionPolicy, events: Vec<Event>, now: i64) -> Vec<Event> {
+    let mut settled : Vec<Event> = events.into_iter().filter(|evt| evt.published || evt.cancelled || evt.expired).collect();
+    settled.sort_by(|a, b| (b.timestamp, b.id).cmp(&(a.timestamp, a.id)));
+
+    let mut pruned : HashSet<uuid::Uuid> = HashSet::new();
+    if let Some(max_age) = policy.max_age {
+        pruned.extend(settled.iter().filter(|evt| evt.timestamp + max_age < now).map(|evt| evt.id));
+    }
+
+    // newest first so everything after the first n of a group is past the limit
+    let mut per_collection : HashMap<uuid::Uuid, usize> = HashMap::new();
+    let mut per_event : HashMap<(uuid::Uuid, String), usize> = HashMap::new();
+    for evt in settled.iter() {
+        let seen = per_collection.entry(evt.collection_id).or_insert(0);
+        *seen += 1;
+        if policy.max_events.map_or(false, |max_events| *seen > max_events) {
+            pruned.insert(evt.id);
+        }
+        let seen = per_event.entry((evt.collection_id, evt.event.clone())).or_insert(0);
+        *seen += 1;
+        if policy.keep_latest.map_or(false, |keep_latest| *seen > keep_latest) {
+            pruned.insert(evt.id);
+        }
+    }
+    settled.into_iter().filter(|evt| pruned.contains(&evt.id)).collect()
+}
+
+// append events as jsonl lines to the archive file of their tenant
+fn archive(archive_path: &str, tenant_id: uuid::Uuid, events: &Vec<Event>) -> std::io::Result<()> {
+    use std::io::Write;
+    std::fs::create_dir_all(archive_path)?;
+    let path = std::path::Path::new(archive_path).join(format!("{}.jsonl", tenant_id));
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    for evt in events.iter() {
+        writeln!(file, "{}", serde_json::to_string(evt).unwrap())?;
+    }
+    file.sync_all()
+}
+
+// delete events with their queue, index and recurrence entries and move the materialized state off them
+fn prune_events(tree: &dyn Store, tenant_id: uuid::Uuid, events: &Vec<Event>) -> Result<(), String> {
+    let mut batch = Batch::default();
+    let mut bytes = 0;
+    for evt in events.iter() {
+        bytes += encode_event(evt).len() as i64;
+        batch.remove(format!("_v_{}", evt.id).as_bytes());
+        batch.remove(pending_key(evt).as_bytes());
+        if let Some(key) = expiry_key(evt) {
+            batch.remove(key.as_bytes());
+        }
+        for key in tree.index_entries(evt) {
+            batch.remove(key.as_bytes());
+        }
+        batch.remove(format!("_o_{}", evt.id).as_bytes());
+    }
+    tree.apply_batch(batch)?;
+    tree.add_storage(tenant_id, -bytes);
+
+    let ids : HashSet<uuid::Uuid> = events.iter().map(|evt| evt.id).collect();
+    for latest in get_state(tree, tenant_id, None).into_iter().filter(|evt| ids.contains(&evt.id)) {
+        refresh_state(tree, tenant_id, &latest.event, latest.collection_id);
+    }
+    persist(tree);
+    Ok(())
+}
+
+// enforce the retention policies of all tenants - returns the number of pruned events
+fn enforce_retention(tree: &dyn Store, archive_path: &str, now: i64) -> Result<usize, String> {
+    let policies : Vec<(uuid::Uuid, RetentionPolicy)> = tree.tenants().into_iter().filter_map(|tenant| {
+        let tenant_id = tenant.id;
+        tenant.settings.retention.map(|policy| (tenant_id, policy))
+    }).collect();
+
+    let mut count = 0;
+    for (tenant_id, policy) in policies {
+        let events = prunable(&policy, tree.tenant_events(tenant_id), now);
+        if events.len() == 0 {
+            continue
+        }
+        if policy.archive.unwrap_or(false) {
+            archive(archive_path, tenant_id, &events).map_err(|e| e.to_string())?;
+        }
+        prune_events(tree, tenant_id, &events)?;
+        count += events.len();
+    }
+    Ok(count)
+}
+
+// a migration upgrading the storage layout to its version from the version before - returns the number of records it changes (without writing in a dry run)
+struct Migration {
+    version: u64,
+    description: &'static str,
+    run: fn(&dyn Store, bool) -> Result<usize, String>,
+}
+
+// the migrations in order of version
+fn migrations() -> Vec<Migration> {
+    vec![
+        Migration{version: 2, description: "add the expiry fields to events", run: migrate_event_expiry},
+        Migration{version: 3, description: "record the encoding of events", run: migrate_event_encoding},
+        Migration{version: 4, description: "create the tenants of existing users and events", run: migrate_tenant_records},
+        Migration{version: 5, description: "count the stored bytes of the events of each tenant", run: migrate_storage_counts},
+    ]
+}
+
+// version of the storage layout - stores written before the version marker are version 1 and empty stores are current
+fn storage_version(tree: &dyn Store) -> u64 {
+    match tree.get(b"_meta_version").unwrap() {
+        Some(v) => std::str::from_utf8(&v).unwrap().parse::<u64>().unwrap(),
+        None => {
+            if tree.scan_prefix(b"_v_").next().is_some() || tree.scan_prefix(b"_u_").next().is_some() {
+                1
+            } else {
+                STORAGE_VERSION
+            }
+        }
+    }
+}
+
+// run the migrations newer than the storage version in order - a dry run reports what each migration would change without writing
+fn migrate(tree: &dyn Store, dry_run: bool) -> Result<Vec<String>, String> {
+    let mut version = storage_version(tree);
+    if version > STORAGE_VERSION {
+        return Err(format!("storage version {} is newer than the version of this broker ({})", version, STORAGE_VERSION))
+    }
+    let pending : Vec<Migration> = migrations().into_iter().filter(|migration| migration.version > version).collect();
+    let mut report : Vec<String> = Vec::new();
+    for migration in pending {
+        let changed = (migration.run)(tree, dry_run).map_err(|e| format!("migration to version {} failed: {}", migration.version, e))?;
+        let verb = if dry_run { "would migrate" } else { "migrated" };
+        report.push(format!("{} from version {} to {} ({}): {} records", verb, version, migration.version, migration.description, changed));
+        if !dry_run {
+            let _ = tree.insert(b"_meta_version", migration.version.to_string().as_bytes());
+            let _ = tree.flush();
+        }
+        version = migration.version;
+    }
+    if !dry_run && tree.get(b"_meta_version").unwrap().is_none() {
+        let _ = tree.insert(b"_meta_encoding", store_encoding(tree).as_bytes());
+        let _ = tree.insert(b"_meta_version", STORAGE_VERSION.to_string().as_bytes());
+        let _ = tree.flush();
+    }
+    Ok(report)
+}
+
+// version 2 - events written before expiry get explicit expires_at and expired fields (read as values so old records always parse)
+fn migrate_event_expiry(tree: &dyn Store, dry_run: bool) -> Result<usize, String> {
+    let mut changed = 0;
+    for x in tree.scan_prefix(b"_v_") {
+        let p = x.map_err(|e| e.to_string())?;
+        let mut value : serde_json::Value = serde_json::from_slice(&p.1).map_err(|e| e.to_string())?;
+        let record = match value.as_object_mut() {
+            Some(record) => record,
+            None => return Err(format!("{} is not an event", std::str::from_utf8(&p.0).unwrap()))
+        };
+        if record.contains_key("expires_at") && record.contains_key("expired") {
+            continue
+        }
+        record.entry("expires_at").or_insert(serde_json::Value::Null);
+        record.entry("expired").or_insert(serde_json::Value::Bool(false));
+
+        // write it back in the field order of events so compare and swap of the serialized event matches
+        let evt : Event = serde_json::from_value(value).map_err(|e| e.to_string())?;
+        if !dry_run {
+            tree.insert(&p.0, serde_json::to_string(&evt).unwrap().as_bytes()).map_err(|e| e.to_string())?;
+        }
+        changed += 1;
+    }
+    Ok(changed)
+}
+
+// version 3 - stores written before the encoding could be chosen keep json events
+fn migrate_event_encoding(tree: &dyn Store, dry_run: bool) -> Result<usize, String> {
+    if !dry_run {
+        tree.insert(b"_meta_encoding", b"json").map_err(|e| e.to_string())?;
+    }
+    Ok(0)
+}
+
+// create a tenant record (named after its id) for every tenant of the users and events without one - moves the retention policies (_l_) into the tenants
+fn migrate_tenant_records(tree: &dyn Store, dry_run: bool) -> Result<usize, String> {
+    let mut ids : Vec<uuid::Uuid> = Vec::new();
+    for x in tree.scan_prefix(b"_u_") {
+        let p = x?;
+        let user : User = serde_json::from_slice(&p.1).map_err(|e| e.to_string())?;
+        ids.push(user.tenant_id);
+    }
+    for x in tree.scan_prefix(b"_v_") {
+        let p = x?;
+        ids.push(try_decode_event(&p.1)?.tenant_id);
+    }
+    let mut policies : HashMap<uuid::Uuid, RetentionPolicy> = HashMap::new();
+    for x in tree.scan_prefix(b"_l_") {
+        let p = x?;
+        let k = std::str::from_utf8(&p.0).map_err(|e| e.to_string())?;
+        let tenant_id = uuid::Uuid::parse_str(&k[3..]).map_err(|e| e.to_string())?;
+        policies.insert(tenant_id, serde_json::from_slice(&p.1).map_err(|e| e.to_string())?);
+        ids.push(tenant_id);
+    }
+
+    let mut created : HashSet<uuid::Uuid> = HashSet::new();
+    let mut batch = Batch::default();
+    for tenant_id in ids {
+        if created.contains(&tenant_id) {
+            continue
+        }
+        // an existing tenant takes the retention policy unless it has one of its own
+        if let Some(mut tenant) = tree.get_tenant(tenant_id) {
+            if let (None, Some(policy)) = (&tenant.settings.retention, policies.get(&tenant_id)) {
+                tenant.settings.retention = Some(policy.clone());
+                batch.insert(tenant_key(tenant_id).as_bytes(), serde_json::to_string(&tenant).unwrap().as_bytes());
+            }
+            continue
+        }
+        let settings = TenantSettings{retention: policies.get(&tenant_id).cloned(), ..TenantSettings::default()};
+        let tenant = Tenant{id: tenant_id, name: tenant_id.to_string(), settings: settings};
+        batch.insert(tenant_key(tenant_id).as_bytes(), serde_json::to_string(&tenant).unwrap().as_bytes());
+        created.insert(tenant_id);
+    }
+    for tenant_id in policies.keys() {
+        batch.remove(format!("_l_{}", tenant_id).as_bytes());
+    }
+    if !dry_run {
+        tree.apply_batch(batch)?;
+    }
+    Ok(created.len())
+}
+
+// version 5 - the stored bytes of the events of each tenant are counted once and then kept up to date as events are written and deleted
+fn migrate_storage_counts(tree: &dyn Store, dry_run: bool) -> Result<usize, String> {
+    let mut bytes : HashMap<uuid::Uuid, u64> = HashMap::new();
+    for x in tree.scan_prefix(b"_v_") {
+        let p = x?;
+        *bytes.entry(try_decode_event(&p.1)?.tenant_id).or_insert(0) += p.1.len() as u64;
+    }
+    let mut batch = Batch::default();
+    for x in tree.scan_prefix(b"_b_") {
+        batch.remove(&x?.0);
+    }
+    for (tenant_id, used) in bytes.iter() {
+        batch.insert(format!("_b_{}", tenant_id).as_bytes(), used.to_string().as_bytes());
+    }
+    if !dry_run {
+        tree.apply_batch(batch)?;
+    }
+    Ok(bytes.len())
+}
+
+// encoding of the events of a store (json or binary) - stores with events and no encoding are json and new stores use the encoding flag
+fn store_encoding(tree: &dyn Store) -> String {
+    match tree.get(b"_meta_encoding").unwrap() {
+        Some(v) => std::str::from_utf8(&v).unwrap().to_owned(),
+        None => {
+            if tree.scan_prefix(b"_v_").next().is_some() || tree.scan_prefix(b"_u_").next().is_some() {
+                "json".to_owned()
+            } else {
+                config().encoding
+            }
+        }
+    }
+}
+
+// encode an event for storage in the encoding of the store
+fn encode_event(evt: &Event) -> Vec<u8> {
+    encode_event_as(evt, *BINARY)
+}
+
+// encode an event as json or binary - binary events start with a zero byte so they are never mistaken for json
+pub fn encode_event_as(evt: &Event, binary: bool) -> Vec<u8> {
+    if binary {
+        let b = BinaryEvent{id: evt.id, user_id: evt.user_id, collection_id: evt.collection_id, tenant_id: evt.tenant_id, event: evt.event.clone(), timestamp: evt.timestamp, published: evt.published, cancelled: evt.cancelled, data: serde_json::to_vec(&evt.data).unwrap(), expires_at: evt.expires_at, expired: evt.expired};
+        let mut bytes = vec![0u8];
+        bytes.extend(bincode::serialize(&b).unwrap());
+        bytes
+    } else {
+        serde_json::to_vec(evt).unwrap()
+    }
+}
+
+// decode a stored event of either encoding
+pub fn decode_event(bytes: &[u8]) -> Event {
+    try_decode_event(bytes).unwrap()
+}
+
+// decode a stored event - an error for bytes that aren't an event of either encoding
+pub fn try_decode_event(bytes: &[u8]) -> Result<Event, String> {
+    if bytes.first() == Some(&0u8) {
+        let b : BinaryEvent = bincode::deserialize(&bytes[1..]).map_err(|e| e.to_string())?;
+        let data = serde_json::from_slice(&b.data).map_err(|e| e.to_string())?;
+        Ok(Event{id: b.id, user_id: b.user_id, collection_id: b.collection_id, tenant_id: b.tenant_id, event: b.event, timestamp: b.timestamp, published: b.published, cancelled: b.cancelled, data: data, expires_at: b.expires_at, expired: b.expired})
+    } else {
+        serde_json::from_slice(bytes).map_err(|e| e.to_string())
+    }
+}
+
+
+// verify the admin secret of an admin request (Authorization: Admin {secret}) - the admin endpoints are disabled without an admin secret
+fn admin_verify(config: Config, token: String) -> bool {
+    let mut parts = token.splitn(2, " ");
+    match (parts.next(), parts.next()) {
+        (Some("Admin"), Some(secret)) => !config.admin_secret.is_empty() && secret == config.admin_secret,
+        _ => false
+    }
+}
+
+// write a jsonl snapshot of the store - the materialized state, the pending/expiry queues, the storage counts and the storage version are left out as they are rebuilt
+// the writes of the store wait while the snapshot is taken
+fn backup(tree: &dyn Store, writer: &mut dyn std::io::Write) -> std::io::Result<usize> {
+    let mut count = 0;
+    let mut failed = None;
+    tree.snapshot(&mut |(k, v)| {
+        let k = std::str::from_utf8(&k).unwrap().to_owned();
+        let line = if k.starts_with("_u_") {
+            BackupLine::User{value: serde_json::from_slice(&v).unwrap()}
+        } else if k.starts_with("_v_") {
+            BackupLine::Event{value: decode_event(&v)}
+        } else if k.starts_with("_s_") || k.starts_with("_p_") || k.starts_with("_e_") || k.starts_with("_b_") || k.starts_with("_meta_") {
+            return true
+        } else {
+            BackupLine::Record{key: k, value: std::str::from_utf8(&v).unwrap().to_owned()}
+        };
+        match writeln!(writer, "{}", serde_json::to_string(&line).unwrap()) {
+            Ok(_) => { count += 1; true },
+            Err(e) => { failed = Some(e); false }
+        }
+    }).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    if let Some(e) = failed {
+        return Err(e)
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+// a writer sending what is written to a streamed response - fails when the client went away
+struct StreamWriter(tokio::sync::mpsc::Sender<Vec<u8>>);
+
+impl std::io::Write for StreamWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        futures::executor::block_on(self.0.send(buf.to_vec())).map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "the client went away"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// write a snapshot of the store to a temporary file and copy it to a writer - the file is gone once it is copied
+fn backup_stream(tree: &dyn Store, mut writer: StreamWriter) -> std::io::Result<u64> {
+    let path = std::env::temp_dir().join(format!("broker_backup_{}.jsonl", Uuid::new_v4()));
+    let written = std::fs::File::create(&path).and_then(|file| backup(tree, &mut std::io::BufWriter::new(file)));
+    let file = written.and_then(|_| std::fs::File::open(&path));
+    let _ = std::fs::remove_file(&path);
+    std::io::copy(&mut file?, &mut writer)
+}
+
+// load a jsonl snapshot into an empty store - nothing is written unless every line is valid
+fn restore<R: std::io::BufRead>(tree: &dyn Store, reader: R) -> Result<usize, String> {
+    if tree.iter().next().is_some() {
+        return Err("the store is not empty".to_owned())
+    }
+    let mut batch = Batch::default();
+    let mut count = 0;
+    for (i, line) in reader.lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().len() == 0 {
+            continue
+        }
+        let parsed : BackupLine = serde_json::from_str(&line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        let (k, v) = match parsed {
+            BackupLine::User{value} => (format!("_u_{}", value.id), serde_json::to_string(&value).unwrap().into_bytes()),
+            BackupLine::Event{value} => (format!("_v_{}", value.id), encode_event(&value)),
+            BackupLine::Record{key, value} => (key, value.into_bytes()),
+        };
+        batch.insert(k.as_bytes(), &v);
+        count += 1;
+    }
+    batch.insert(b"_meta_version", STORAGE_VERSION.to_string().as_bytes());
+    batch.insert(b"_meta_encoding", config().encoding.as_bytes());
+    tree.apply_batch(batch).map_err(|e| e.to_string())?;
+
+    // backups from before tenant records get a tenant for every tenant id
+    migrate_tenant_records(tree, false)?;
+    migrate_storage_counts(tree, false)?;
+    let _ = tree.flush();
+    Ok(count)
+}
+
+// send the events of a tenant (optionally of a collection and time range) as jsonl lines - stops when send returns false
+fn export(tree: &dyn Store, tenant_id: uuid::Uuid, query: &ExportQuery, send: &mut dyn FnMut(String) -> bool) {
+    for x in tree.scan_prefix(b"_v_") {
+        let p = x.unwrap();
+        let evt = decode_event(&p.1);
+        let wanted = evt.tenant_id == tenant_id
+            && query.collection_id.map_or(true, |collection_id| evt.collection_id == collection_id)
+            && query.from.map_or(true, |from| evt.timestamp >= from)
+            && query.to.map_or(true, |to| evt.timestamp <= to);
+        if wanted && !send(format!("{}\n", serde_json::to_string(&evt).unwrap())) {
+            return
+        }
+    }
+}
+
+// import jsonl events (as exported) optionally into another tenant and with new ids - nothing is written unless every line is valid
+fn import(tree: &dyn Store, body: &[u8], query: ImportQuery) -> (bool, String) {
+
+    let body = match std::str::from_utf8(body) {
+        Ok(body) => body,
+        Err(_) => return (false, json!({"error": "body must be utf-8"}).to_string())
+    };
+    let keep_ids = query.keep_ids.unwrap_or(true);
+
+    let mut events : Vec<Event> = Vec::new();
+    let mut ids : HashSet<uuid::Uuid> = HashSet::new();
+    for (i, line) in body.lines().enumerate() {
+        if line.trim().len() == 0 {
+            continue
+        }
+        let mut evt : Event = match serde_json::from_str(line) {
+            Ok(evt) => evt,
+            Err(e) => return (false, json!({"error": format!("line {}: {}", i + 1, e)}).to_string())
+        };
+        if let Some(tenant_id) = query.tenant_id {
+            evt.tenant_id = tenant_id;
+        }
+        if tree.get_tenant(evt.tenant_id).is_none() {
+            return (false, json!({"error": format!("line {}: unknown tenant {}", i + 1, evt.tenant_id)}).to_string())
+        }
+        if !keep_ids {
+            evt.id = Uuid::new_v4();
+        } else if !ids.insert(evt.id) || tree.contains_key(format!("_v_{}", evt.id).as_bytes()).unwrap() {
+            return (false, json!({"error": format!("line {}: event {} already exists", i + 1, evt.id)}).to_string())
+        }
+        events.push(evt);
+    }
+
+    // write the events with their queue and index entries in one batch
+    let mut batch = Batch::default();
+    for evt in events.iter() {
+        let id = evt.id.to_string();
+        batch.insert(format!("_v_{}", id).as_bytes(), &encode_event(evt));
+        if !evt.published && !evt.cancelled && !evt.expired {
+            batch.insert(pending_key(evt).as_bytes(), id.as_bytes());
+        }
+        if !evt.cancelled && !evt.expired {
+            if let Some(key) = expiry_key(evt) {
+                batch.insert(key.as_bytes(), id.as_bytes());
+            }
+        }
+        for key in tree.index_entries(evt) {
+            batch.insert(key.as_bytes(), id.as_bytes());
+        }
+    }
+    if let Err(e) = tree.apply_batch(batch) {
+        return (false, json!({"error": e.to_string()}).to_string())
+    }
+    for evt in events.iter() {
+        tree.add_storage(evt.tenant_id, encode_event(evt).len() as i64);
+    }
+    for evt in events.iter().filter(|evt| evt.published && !evt.cancelled && !evt.expired) {
+        update_state(tree, evt);
+    }
+    persist(tree);
+
+    (true, json!({"imported": events.len()}).to_string())
+}
+
+// run the backup (to a file or stdout), restore (from a file or stdin) or migrate command - returns false when there is no command
+fn run_command(command: &Vec<String>) -> bool {
+    let tree = store();
+    match command.first().map(|c| c.as_str()) {
+        Some("backup") => {
+            let result = match command.get(1) {
+                Some(path) => std::fs::File::create(path).and_then(|file| backup(tree, &mut std::io::BufWriter::new(file))),
+                None => backup(tree, &mut std::io::stdout())
+            };
+            match result {
+                Ok(count) => eprintln!("backed up {} records", count),
+                Err(e) => { eprintln!("backup failed: {}", e); std::process::exit(1) }
+            }
+            true
+        },
+        Some("restore") => {
+            let result = match command.get(1) {
+                Some(path) => std::fs::File::open(path).map_err(|e| e.to_string()).and_then(|file| restore(tree, std::io::BufReader::new(file))),
+                None => restore(tree, std::io::stdin().lock())
+            };
+            match result {
+                Ok(count) => eprintln!("restored {} records", count),
+                Err(e) => { eprintln!("restore failed: {}", e); std::process::exit(1) }
+            }
+            true
+        },
+        Some("migrate") => {
+            let dry_run = config().dry_run;
+            match migrate(tree, dry_run) {
+                Ok(report) => {
+                    for line in report.iter() {
+                        eprintln!("{}", line);
+                    }
+                    if report.is_empty() {
+                        eprintln!("storage is up to date at version {}", storage_version(tree));
+                    }
+                },
+                Err(e) => { eprintln!("{}", e); std::process::exit(1) }
+            }
+            true
+        },
+        Some(other) => { eprintln!("unknown command: {}", other); std::process::exit(1) },
+        None => false
+    }
+}
+
+// publish the due events from the pending queue and expire the due events from the expiry queue
+fn dispatch(tree: &dyn Store, tx: &Arc<Mutex<bus::Bus<Event>>>) {
+
+    // only get the time when events are pending or expiring
+    if tree.scan_prefix(b"_p_").next().is_none() && tree.scan_prefix(b"_e_").next().is_none() {
+        return
+    }
+    let now = get_ntp_time();
+
+    // get the due events from the pending queue (ordered by timestamp) that have not been published or cancelled
+    let mut vals : Vec<Event> = Vec::new();
+    for event_id in tree.take_due("_p_", now) {
+        if let Some(evt) = tree.get_event(&event_id) {
+            if !evt.published && !evt.cancelled && !evt.expired {
+                vals.push(evt);
+            }
+        }
+    }
+
+    // publish these filtered events to bus
+    for v in vals {
+        let mut new_json = v.clone();
+        new_json.published = true;
+        let published = tree.swap_event(&v, &new_json);
+        if published {
+            update_state(tree, &new_json);
+            advance_recurrence(tree, &new_json, now);
+            enqueue_deliveries(tree, &new_json, now);
+        }
+        persist(tree);
+
+        // skip events cancelled or rescheduled while publishing
+        if published {
+            tx.lock().unwrap().broadcast(new_json);
+        }
+    }
+
+    // expire the due events from the expiry queue and notify subscribers on the bus
+    for event_id in tree.take_due("_e_", now) {
+        if let Some(evt) = expire(tree, &event_id) {
+            tx.lock().unwrap().broadcast(evt);
+        }
+    }
+}
+
+// create a sse event
+fn event_stream(rx: crossbeam::channel::Receiver<SSE>, allowed: bool) -> Result<impl ServerSentEvent, Infallible> {
+
+    if allowed {
+        let sse = match rx.try_recv() {
+            Ok(sse) => sse,
+            Err(_) => {
+                let id = Uuid::new_v4();
+                let guid = id.to_string();
+                let polling = json!({"status": "polling"});
+                SSE{id: guid, event: "internal_status".to_owned(), data: polling.to_string(), retry: Duration::from_millis(5000), tenant_id: id}
+            }
+        };
+        Ok((
+            warp::sse::id(sse.id),
+            warp::sse::data(sse.data),
+            warp::sse::event(sse.event),
+            warp::sse::retry(sse.retry),
+        ))
+    } else {
+        let id = Uuid::new_v4();
+        let guid = id.to_string();
+        let denied = json!({"error": "denied"});
+        let sse = SSE{id: guid, event: "internal_status".to_owned(), data: denied.to_string(), retry: Duration::from_millis(5000), tenant_id: id};
+        Ok((
+            warp::sse::id(sse.id),
+            warp::sse::data(sse.data),
+            warp::sse::event(sse.event),
+            warp::sse::retry(sse.retry),
+        ))
+    }
+}
+
+// main function
+pub async fn broker() {
+
+    // start logging
+    pretty_env_logger::init();
+
+    // run a backup, restore or migrate command instead of the server
+    if run_command(&config().command) {
+        return
+    }
+
+    // upgrade the storage layout before anything reads the records
+    match migrate(store(), false) {
+        Ok(report) => {
+            for line in report {
+                eprintln!("{}", line);
+            }
+        },
+        Err(e) => { eprintln!("{}", e); std::process::exit(1) }
+    }
+    if config().admin_secret.is_empty() {
+        eprintln!("the admin endpoints are disabled - pass an admin-secret to enable them");
+    }
+
+    // user create route
+    let user_create_route = warp::post()
+        .and(warp::path("users"))
+        .and(warp::body::json())
+        .and_then(move |user: UserForm| async move {
+            // hashing and writing block so run them off the async runtime
+            let (check, value) = tokio::task::spawn_blocking(move || {
+                let tree = store();
+                user_create(tree, user)
+            }).await.unwrap();
+            if check {
+                let reply = warp::reply::with_status(value, StatusCode::OK);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status(value, StatusCode::BAD_REQUEST);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+    
+    // auth check middleware
+    let auth_check = warp::header::<String>("authorization").and_then(|token: String| async move {
+        // basic auth reads the users and verifies the password so run it off the async runtime
+        let jwt = tokio::task::spawn_blocking(move || {
+            let configure = config();
+            jwt_verify(configure, token)
+        }).await.unwrap();
+        Ok::<_, Infallible>(jwt)
+    });
+
+    // login route
+    let login_route = warp::post()
+        .and(warp::path("login"))
+        .and(warp::body::json())
+        .and_then(move |login_form: Login| async move {
+            let (check, value) = tokio::task::spawn_blocking(move || {
+                let configure = config();
+                let tree = store();
+                login(tree, login_form.clone(), configure.clone())
+            }).await.unwrap();
+            if check {
+                let reply = warp::reply::with_status(value, StatusCode::OK);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status(value, StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // insert route
+    let insert_route = warp::post()
+        .and(warp::path("insert"))
+        .and(warp::path::end())
+        .and(auth_check)
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(warp::body::json())
+        .and_then(move |jwt: JWT, idempotency_key: Option<String>, event_form: EventForm| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let configure = config();
+                    let tree = store();
+                    insert(tree, jwt.claims.sub, event_form, idempotency_key, configure)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // batch insert route
+    let insert_batch_route = warp::post()
+        .and(warp::path("insert"))
+        .and(warp::path("batch"))
+        .and(auth_check)
+        .and(warp::query::<BatchQuery>())
+        .and(warp::body::bytes())
+        .and_then(move |jwt: JWT, batch_query: BatchQuery, body: warp::hyper::body::Bytes| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    insert_batch(tree, jwt.claims.sub, &body, batch_query.atomic.unwrap_or(false))
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // build the materialized state of the latest events and the pending queue from the stored events
+    rebuild_state(store());
+    rebuild_pending(store());
+
+    // resume the purges interrupted by a restart in the background
+    for tenant_id in resume_purges(store()) {
+        let _ = tokio::task::spawn_blocking(move || purge_tenant(store(), tenant_id));
+    }
+
+    // create thread-safe broadcast bus
+    let mix_tx = Bus::new(100);
+    let tx = Arc::new(Mutex::new(mix_tx));
+    let tx2 = tx.clone();
+
+    // create tokio worker thread that will dispatch events to bus - the sled and ntp work runs on the blocking pool
+    let _ = tokio::spawn(async move {
+        let mut ticks = interval(Duration::from_millis(100));
+        loop {
+            ticks.tick().await;
+            let tx3 = tx2.clone();
+            let _ = tokio::task::spawn_blocking(move || {
+                let tree = store();
+                dispatch(tree, &tx3);
+            }).await;
+        }  
+    });
+    
+    // create tokio worker thread that will post the due webhook deliveries every second - the sled and ntp work runs on the blocking pool
+    let _ = tokio::spawn(async move {
+        let tls = native_tls::TlsConnector::new().unwrap();
+        let allow_private = config().webhook_allow_private;
+        let mut ticks = interval(Duration::from_secs(1));
+        loop {
+            ticks.tick().await;
+            let due = tokio::task::spawn_blocking(move || {
+                let tree = store();
+                if tree.scan_prefix(b"_d_").next().is_none() {
+                    return None
+                }
+                let now = get_ntp_time();
+                Some((now, due_deliveries(tree, now)))
+            }).await;
+            let (now, due) = match due {
+                Ok(Some(due)) => due,
+                _ => continue
+            };
+            let sends = due.into_iter().map(|(delivery, webhook)| {
+                let tls = tls.clone();
+                async move {
+                    let result = match &webhook {
+                        Some(webhook) => send_delivery(&tls, webhook, &delivery, allow_private).await,
+                        None => Ok(())
+                    };
+                    (delivery, result)
+                }
+            });
+            let results = futures::future::join_all(sends).await;
+            let _ = tokio::task::spawn_blocking(move || {
+                let configure = config();
+                let tree = store();
+                for (delivery, result) in results {
+                    record_delivery(tree, delivery, result, now, &configure);
+                }
+            }).await;
+        }
+    });
+
+    // create tokio worker thread that will flush writes together every group-commit-ms in group durability mode
+    let configure = config();
+    if configure.durability == "group" {
+        let _ = tokio::spawn(async move {
+            let mut ticks = interval(Duration::from_millis(configure.group_commit_ms));
+            loop {
+                ticks.tick().await;
+                if DIRTY.swap(false, Ordering::SeqCst) {
+                    let _ = tokio::task::spawn_blocking(move || {
+                        let tree = store();
+                        let _ = tree.flush();
+                    }).await;
+                }
+            }
+        });
+    }
+
+    // create tokio worker thread that will prune expired idempotency keys every hour
+    let _ = tokio::spawn(async move {
+        let mut ticks = interval(Duration::from_secs(3600));
+        loop {
+            ticks.tick().await;
+            let _ = tokio::task::spawn_blocking(move || {
+                let configure = config();
+                let tree = store();
+                prune_idempotency_keys(tree, configure.idempotency_window);
+                prune_usage(tree, get_ntp_time());
+            }).await;
+        }
+    });
+
+    // create tokio worker thread that will enforce the retention policies every retention-interval seconds
+    let retention_interval = config().retention_interval;
+    let _ = tokio::spawn(async move {
+        let mut ticks = interval(Duration::from_secs(retention_interval));
+        loop {
+            ticks.tick().await;
+            let _ = tokio::task::spawn_blocking(move || {
+                let configure = config();
+                let tree = store();
+                if let Err(e) = enforce_retention(tree, &configure.archive_path, get_ntp_time()) {
+                    log::error!("retention: {}", e);
+                }
+            }).await;
+        }
+    });
+
+    // create bus middleware
+    let with_sender = warp::any().map(move || tx.clone());
+    let with_cancel_sender = with_sender.clone();
+    let with_retract_sender = with_sender.clone();
+    let with_recurrence_sender = with_sender.clone();
+    let with_recurrence_delete_sender = with_sender.clone();
+    let with_erase_sender = with_sender.clone();
+
+    // sse route
+    let sse_route = warp::path("events")
+        .and(auth_check)
+        .and(with_sender)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::get()).and_then(move |jwt: JWT, tx_main: Arc<Mutex<bus::Bus<Event>>>, tenant_id: uuid::Uuid, origin: Option<String>| async move {
+
+        // create recv for bus (each sse instance must have its own)
+        let mut rx_main = tx_main.lock().unwrap().add_rx();
+
+        // create local sse channel
+        let (tx, rx) = unbounded();
+
+        // loop through sse events to send on load of sse route
+        let (allowed, max_subscribers, events) = tokio::task::spawn_blocking(move || {
+            let tree = store();
+            let max_subscribers = tree.get_tenant(tenant_id).and_then(|tenant| tenant.settings.limits).and_then(|limits| limits.max_subscribers);
+            let allowed = tree.get_tenant(tenant_id).is_some() && origin_allowed(tree, tenant_id, origin.as_deref());
+            (allowed, max_subscribers, get_events(tenant_id))
+        }).await.unwrap();
+
+        // count the stream against the subscribers of the tenant while it is open
+        let allowed = jwt.check && allowed;
+        let subscription = if allowed {
+            match Subscription::open(tenant_id, max_subscribers) {
+                Some(subscription) => Some(subscription),
+                None => {
+                    let reply = warp::reply::with_status(json!({"error": "tenant has reached its maximum of subscribers"}).to_string(), StatusCode::TOO_MANY_REQUESTS);
+                    return Ok::<_, Infallible>(Box::new(warp::reply::with_header(reply, "Content-Type", "application/json")) as Box<dyn warp::Reply>)
+                }
+            }
+        } else {
+            None
+        };
+        for event in events {
+            let _ = tx.send(event);
+        }
+
+        // every 100ms check the bus and if any messages send to local channel also check local channel and publish to stream (sse route)
+        // the stream ends when the tenant is purged
+        let ticks = interval(Duration::from_millis(100)).take_while(move |_| !purging(tenant_id));
+        let event_stream = futures::StreamExt::then(ticks, move |_| {
+            let evt = match rx_main.try_recv() {
+                Ok(evt) => {
+                    if tenant_id == evt.tenant_id {
+                        evt
+                    } else {
+                        let id = Uuid::new_v4();
+                        Event{id: id, published: false, cancelled: false, data: json!({"test": "test"}), event: "fake".to_owned(), timestamp: 123, user_id: id, collection_id: id, tenant_id: id, expires_at: None, expired: false}
+                    }
+                },
+                Err(_) => {
+                    let id = Uuid::new_v4();
+                    Event{id: id, published: false, cancelled: false, data: json!({"test": "test"}), event: "fake".to_owned(), timestamp: 123, user_id: id, collection_id: id, tenant_id: id, expires_at: None, expired: false}
+                }
+            };
+            // notify of cancelled (retracted) and expired events before sending the latest events
+            if evt.cancelled || evt.expired {
+                let name = if evt.cancelled { "internal_cancelled" } else { "internal_expired" };
+                let notification = json!({"event": evt});
+                let _ = tx.send(SSE{id: Uuid::new_v4().to_string(), event: name.to_owned(), data: notification.to_string(), retry: Duration::from_millis(5000), tenant_id: evt.tenant_id});
+            }
+            // the subscription is dropped with the stream when the client goes away
+            let _ = &subscription;
+            let (tx, rx) = (tx.clone(), rx.clone());
+            async move {
+                // only read the latest events when this tenant had an update and do it off the async runtime
+                if evt.tenant_id == tenant_id {
+                    let events = tokio::task::spawn_blocking(move || get_events(tenant_id)).await.unwrap();
+                    for event in events {
+                        let _ = tx.send(event);
+                    }
+                }
+                event_stream(rx, allowed)
+            }
+        });
+        Ok(Box::new(warp::sse::reply(event_stream)) as Box<dyn warp::Reply>)
+    });
+
+    // cancel route
+    let cancel_route = warp::post().or(warp::delete()).unify()
+        .and(warp::path("cancel"))
+        .and(auth_check)
+        .and(with_cancel_sender)
+        .and(warp::path::param::<String>())
+        .and_then(move |jwt: JWT, tx_main: Arc<Mutex<bus::Bus<Event>>>, event_id: String| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    cancel(tree, event_id, jwt.claims.sub, false, tx_main)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // reschedule route
+    let reschedule_route = warp::post()
+        .and(warp::path("reschedule"))
+        .and(auth_check)
+        .and(warp::path::param::<String>())
+        .and(warp::body::json())
+        .and_then(move |jwt: JWT, event_id: String, form: RescheduleForm| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    reschedule(tree, event_id, jwt.claims.sub, form)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // create recurring event route
+    let recurrence_create_route = warp::post()
+        .and(warp::path("recurring"))
+        .and(warp::path::end())
+        .and(auth_check)
+        .and(warp::body::json())
+        .and_then(move |jwt: JWT, form: RecurrenceForm| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    recurrence_create(tree, jwt.claims.sub, form)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // recurring events route
+    let recurrences_route = warp::get()
+        .and(warp::path("recurring"))
+        .and(auth_check)
+        .and_then(move |jwt: JWT| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    recurrences(tree, jwt.claims.sub)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // pause or resume recurring event route
+    let recurrence_update_route = warp::post()
+        .and(warp::path("recurring"))
+        .and(auth_check)
+        .and(with_recurrence_sender)
+        .and(warp::path::param::<String>())
+        .and(warp::path::param::<String>())
+        .and_then(move |jwt: JWT, tx_main: Arc<Mutex<bus::Bus<Event>>>, id: String, action: String| async move {
+            if jwt.check && (action == "pause" || action == "resume") {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    recurrence_update(tree, jwt.claims.sub, id, &action, tx_main)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else if jwt.check {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::NOT_FOUND);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // delete recurring event route
+    let recurrence_delete_route = warp::delete()
+        .and(warp::path("recurring"))
+        .and(auth_check)
+        .and(with_recurrence_delete_sender)
+        .and(warp::path::param::<String>())
+        .and_then(move |jwt: JWT, tx_main: Arc<Mutex<bus::Bus<Event>>>, id: String| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    recurrence_update(tree, jwt.claims.sub, id, "delete", tx_main)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // retract route
+    let retract_route = warp::post()
+        .and(warp::path("retract"))
+        .and(auth_check)
+        .and(with_retract_sender)
+        .and(warp::path::param::<String>())
+        .and_then(move |jwt: JWT, tx_main: Arc<Mutex<bus::Bus<Event>>>, event_id: String| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    cancel(tree, event_id, jwt.claims.sub, true, tx_main)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
             } else {
                 let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
             }
         });
 
@@ -776,15 +3711,18 @@ pub async fn broker() {
         .and(warp::path("collections"))
         .and(auth_check)
         .and(warp::path::param::<String>())
-        .map(move |jwt: JWT, collection_id: String| {
+        .and(warp::query::<Page>())
+        .and_then(move |jwt: JWT, collection_id: String, page: Page| async move {
             if jwt.check {
-                let tree = TREE.get(&"tree".to_owned()).unwrap();
-                let record = collection(tree.clone(), collection_id, jwt.claims.sub);
-                let reply = warp::reply::with_status(record, StatusCode::OK);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    collection(tree, collection_id, jwt.claims.sub, page)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
             } else {
                 let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
             }
         });
 
@@ -792,29 +3730,660 @@ pub async fn broker() {
     let user_collection_route = warp::get()
         .and(warp::path("user_events"))
         .and(auth_check)
-        .map(move |jwt: JWT| {
+        .and(warp::query::<Page>())
+        .and_then(move |jwt: JWT, page: Page| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    user_collection(tree, jwt.claims.sub, page)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // set columns route
+    let columns_set_route = warp::post()
+        .and(warp::path("columns"))
+        .and(auth_check)
+        .and(warp::body::json())
+        .and_then(move |jwt: JWT, form: ColumnForm| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    columns_set(tree, jwt.claims.sub, form)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // get columns route
+    let columns_route = warp::get()
+        .and(warp::path("columns"))
+        .and(auth_check)
+        .and(warp::path::param::<String>())
+        .and_then(move |jwt: JWT, event: String| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    columns(tree, jwt.claims.sub, event)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // state route
+    let state_route = warp::get()
+        .and(warp::path("state"))
+        .and(auth_check)
+        .and(warp::path::param::<String>())
+        .and_then(move |jwt: JWT, event: String| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    state(tree, jwt.claims.sub, event)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // query route
+    let query_route = warp::post()
+        .and(warp::path("query"))
+        .and(auth_check)
+        .and(warp::body::json())
+        .and_then(move |jwt: JWT, form: QueryForm| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    query(tree, jwt.claims.sub, form)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // aggregate route
+    let aggregate_route = warp::post()
+        .and(warp::path("aggregate"))
+        .and(auth_check)
+        .and(warp::body::json())
+        .and_then(move |jwt: JWT, form: AggregateForm| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    aggregate(tree, jwt.claims.sub, form)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // create index route
+    let index_create_route = warp::post()
+        .and(warp::path("indexes"))
+        .and(auth_check)
+        .and(warp::body::json())
+        .and_then(move |jwt: JWT, form: IndexForm| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    index_create(tree, jwt.claims.sub, form)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // remove index route
+    let index_remove_route = warp::delete()
+        .and(warp::path("indexes"))
+        .and(auth_check)
+        .and(warp::path::param::<String>())
+        .and_then(move |jwt: JWT, field: String| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    index_remove(tree, jwt.claims.sub, field)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // indexes route
+    let indexes_route = warp::get()
+        .and(warp::path("indexes"))
+        .and(auth_check)
+        .and_then(move |jwt: JWT| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    indexes(tree, jwt.claims.sub)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // webhook routes
+    let webhook_create_route = warp::post()
+        .and(warp::path("webhooks"))
+        .and(auth_check)
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and_then(move |jwt: JWT, form: WebhookForm| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let configure = config();
+                    let tree = store();
+                    webhook_create(tree, jwt.claims.sub, form, configure)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let webhooks_route = warp::get()
+        .and(warp::path("webhooks"))
+        .and(auth_check)
+        .and(warp::path::end())
+        .and_then(move |jwt: JWT| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    webhooks(tree, jwt.claims.sub)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let webhook_remove_route = warp::delete()
+        .and(warp::path("webhooks"))
+        .and(auth_check)
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(move |jwt: JWT, id: String| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    webhook_remove(tree, jwt.claims.sub, id)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let dead_letters_route = warp::get()
+        .and(warp::path("webhooks"))
+        .and(auth_check)
+        .and(warp::path("dead_letters"))
+        .and(warp::path::end())
+        .and_then(move |jwt: JWT| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    dead_letters(tree, jwt.claims.sub)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let dead_letter_retry_route = warp::post()
+        .and(warp::path("webhooks"))
+        .and(auth_check)
+        .and(warp::path("dead_letters"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(move |jwt: JWT, id: String| async move {
+            if jwt.check {
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    dead_letter_update(tree, jwt.claims.sub, id, true)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let dead_letter_remove_route = warp::delete()
+        .and(warp::path("webhooks"))
+        .and(auth_check)
+        .and(warp::path("dead_letters"))
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(move |jwt: JWT, id: String| async move {
             if jwt.check {
-                let tree = TREE.get(&"tree".to_owned()).unwrap();
-                let record = user_collection(tree.clone(), jwt.claims.sub);
+                let (status, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    dead_letter_update(tree, jwt.claims.sub, id, false)
+                }).await.unwrap();
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // admin check middleware
+    let admin_check = warp::header::<String>("authorization").map(|token| {
+        let configure = config();
+        admin_verify(configure, token)
+    });
+
+    // admin backup route
+    let backup_route = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("backup"))
+        .and(admin_check)
+        .map(move |admin: bool| {
+            if admin {
+                // take the snapshot into a temporary file and only then stream it - the writes of the store wait for the disk, not for the client
+                let (tx, rx) = tokio::sync::mpsc::channel::<Vec<u8>>(64);
+                let _ = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    if let Err(e) = backup_stream(tree, StreamWriter(tx)) {
+                        log::error!("backup failed: {}", e);
+                    }
+                });
+                let body = warp::hyper::Body::wrap_stream(rx.map(Ok::<_, Infallible>));
+                warp::http::Response::builder().status(StatusCode::OK).header("Content-Type", "application/x-ndjson").body(body).unwrap()
+            } else {
+                warp::http::Response::builder().status(StatusCode::UNAUTHORIZED).header("Content-Type", "application/x-ndjson").body(warp::hyper::Body::empty()).unwrap()
+            }
+        });
+
+    // admin export route - streams the jsonl lines as they are read
+    let export_route = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("export"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::query::<ExportQuery>())
+        .map(move |admin: bool, tenant_id: uuid::Uuid, query: ExportQuery| {
+            if admin {
+                let (mut tx, rx) = tokio::sync::mpsc::channel::<String>(64);
+                let _ = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    export(tree, tenant_id, &query, &mut |line| futures::executor::block_on(tx.send(line)).is_ok());
+                });
+                let body = warp::hyper::Body::wrap_stream(rx.map(Ok::<_, Infallible>));
+                warp::http::Response::builder().status(StatusCode::OK).header("Content-Type", "application/x-ndjson").body(body).unwrap()
+            } else {
+                warp::http::Response::builder().status(StatusCode::UNAUTHORIZED).header("Content-Type", "application/x-ndjson").body(warp::hyper::Body::empty()).unwrap()
+            }
+        });
+
+    // admin import route
+    let import_route = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("import"))
+        .and(admin_check)
+        .and(warp::query::<ImportQuery>())
+        .and(warp::body::bytes())
+        .and_then(move |admin: bool, query: ImportQuery, body: warp::hyper::body::Bytes| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    import(tree, &body, query)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // admin tenant routes
+    let tenant_create_route = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("tenants"))
+        .and(admin_check)
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and_then(move |admin: bool, form: TenantForm| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    tenant_create(tree, form)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let tenants_route = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("tenants"))
+        .and(admin_check)
+        .and(warp::path::end())
+        .and_then(move |admin: bool| async move {
+            if admin {
+                let record = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    tenants(tree)
+                }).await.unwrap();
                 let reply = warp::reply::with_status(record, StatusCode::OK);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let tenant_route = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("tenants"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path::end())
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    tenant(tree, tenant_id)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let tenant_update_route = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("tenants"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid, form: TenantUpdateForm| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    tenant_update(tree, tenant_id, form)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let tenant_remove_route = warp::delete()
+        .and(warp::path("admin"))
+        .and(warp::path("tenants"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path::end())
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    tenant_remove(tree, tenant_id)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let tenant_usage_route = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("tenants"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path("usage"))
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    tenant_usage(tree, tenant_id, get_ntp_time())
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // admin tenant purge routes - the purge runs in the background
+    let purge_route = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("tenants"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path("purge"))
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    purge_start(tree, tenant_id)
+                }).await.unwrap();
+                if check {
+                    let _ = tokio::task::spawn_blocking(move || purge_tenant(store(), tenant_id));
+                }
+                let status = if check { StatusCode::ACCEPTED } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let purge_status_route = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("tenants"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path("purge"))
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    purge_status(tree, tenant_id)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // admin user export route - a downloadable json archive
+    let user_export_route = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("users"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path("export"))
+        .and_then(move |admin: bool, user_id: uuid::Uuid| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    user_export(tree, user_id)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                let reply = warp::reply::with_header(reply, "Content-Disposition", format!("attachment; filename=\"user-{}.json\"", user_id));
+                Ok::<_, Infallible>(Box::new(warp::reply::with_header(reply, "Content-Type", "application/json")) as Box<dyn warp::Reply>)
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(Box::new(warp::reply::with_header(reply, "Content-Type", "application/json")) as Box<dyn warp::Reply>)
+            }
+        });
+
+    // admin user erasure route
+    let user_erase_route = warp::delete()
+        .and(warp::path("admin"))
+        .and(warp::path("users"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::path::end())
+        .and(warp::query::<ErasureQuery>())
+        .and(with_erase_sender)
+        .and_then(move |admin: bool, user_id: uuid::Uuid, query: ErasureQuery, tx_main: Arc<Mutex<bus::Bus<Event>>>| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    user_erase(tree, user_id, query, tx_main)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    // admin retention policy routes
+    let retention_set_route = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("retention"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and(warp::body::json())
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid, policy: RetentionPolicy| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    retention_set(tree, tenant_id, policy)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let retention_route = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("retention"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    retention(tree, tenant_id)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            } else {
+                let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
+            }
+        });
+
+    let retention_remove_route = warp::delete()
+        .and(warp::path("admin"))
+        .and(warp::path("retention"))
+        .and(admin_check)
+        .and(warp::path::param::<uuid::Uuid>())
+        .and_then(move |admin: bool, tenant_id: uuid::Uuid| async move {
+            if admin {
+                let (check, record) = tokio::task::spawn_blocking(move || {
+                    let tree = store();
+                    retention_remove(tree, tenant_id)
+                }).await.unwrap();
+                let status = if check { StatusCode::OK } else { StatusCode::BAD_REQUEST };
+                let reply = warp::reply::with_status(record, status);
+                Ok::<_, Infallible>(warp::reply::with_header(reply, "Content-Type", "application/json"))
             } else {
                 let reply = warp::reply::with_status("".to_owned(), StatusCode::UNAUTHORIZED);
-                warp::reply::with_header(reply, "Content-Type", "application/json")
+                Ok(warp::reply::with_header(reply, "Content-Type", "application/json"))
             }
         });
 
     // create cors wrapper
     let configure = config();
-    let mut cors = warp::cors().allow_origin(&*configure.origin).allow_methods(vec!["GET", "POST"]).allow_headers(vec![warp::http::header::AUTHORIZATION, warp::http::header::CONTENT_TYPE]);
+    let cors = warp::cors().allow_methods(vec!["GET", "POST", "DELETE"]).allow_headers(vec![warp::http::header::AUTHORIZATION, warp::http::header::CONTENT_TYPE]).allow_header("idempotency-key");
 
-    // handle allow any origin case
-    if configure.origin == "*" {
-        cors = warp::cors().allow_any_origin().allow_methods(vec!["GET", "POST"]).allow_headers(vec![warp::http::header::AUTHORIZATION, warp::http::header::CONTENT_TYPE]);
-    }
+    // handle allow any origin case - tenants can then have their own origin
+    let cors = if configure.origin == "*" {
+        cors.allow_any_origin()
+    } else {
+        cors.allow_origin(&*configure.origin)
+    };
+
+    // group the admin routes into one response type to keep the type of the routes shallow
+    let admin_routes = backup_route.or(export_route).or(import_route).or(retention_set_route).or(retention_route).or(retention_remove_route).or(tenant_create_route).or(tenants_route).or(tenant_route).or(tenant_update_route).or(tenant_remove_route).or(tenant_usage_route).or(purge_route).or(purge_status_route).or(user_export_route).or(user_erase_route).map(|reply| warp::Reply::into_response(reply));
+
+    // group the webhook routes the same way
+    let webhook_routes = webhook_create_route.or(webhooks_route).or(webhook_remove_route).or(dead_letters_route).or(dead_letter_retry_route).or(dead_letter_remove_route).map(|reply| warp::Reply::into_response(reply));
 
     // create routes
-    let routes = warp::any().and(login_route).or(user_create_route).or(insert_route).or(sse_route).or(cancel_route).or(collections_route).or(user_collection_route).with(cors);
+    let routes = warp::any().and(login_route).or(user_create_route).or(insert_batch_route).or(insert_route).or(sse_route).or(cancel_route).or(retract_route).or(reschedule_route).or(recurrence_create_route).or(recurrences_route).or(recurrence_update_route).or(recurrence_delete_route).or(collections_route).or(user_collection_route).or(columns_set_route).or(columns_route).or(state_route).or(query_route).or(aggregate_route).or(index_create_route).or(index_remove_route).or(indexes_route).or(webhook_routes).or(admin_routes).with(cors);
 
     // set ip and port
     let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), configure.port);
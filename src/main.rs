@@ -1,7 +1,6 @@
-mod lib;
-use lib::broker;
+use broker::broker;
 
 #[tokio::main]
 pub async fn main() {
     broker().await
-}
\ No newline at end of file
+}
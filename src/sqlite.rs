@@ -0,0 +1,289 @@
+use std::sync::Mutex;
+use rusqlite::{Connection, OptionalExtension, Transaction, params};
+use crate::{User, Event, decode_event, try_decode_event};
+use crate::store::{Store, Batch, Entry};
+
+// the tables of the store - users, events and the pending and expiry queues get their own columns and everything else is a plain record
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        key TEXT PRIMARY KEY,
+        id TEXT,
+        username TEXT,
+        collection_id TEXT,
+        tenant_id TEXT,
+        value BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS users_username ON users (username);
+    CREATE INDEX IF NOT EXISTS users_tenant ON users (tenant_id);
+
+    CREATE TABLE IF NOT EXISTS events (
+        key TEXT PRIMARY KEY,
+        id TEXT,
+        user_id TEXT,
+        collection_id TEXT,
+        tenant_id TEXT,
+        event TEXT,
+        timestamp INTEGER,
+        published INTEGER,
+        cancelled INTEGER,
+        expires_at INTEGER,
+        expired INTEGER,
+        data TEXT,
+        value BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_tenant ON events (tenant_id, timestamp);
+    CREATE INDEX IF NOT EXISTS events_collection ON events (collection_id, timestamp);
+    CREATE INDEX IF NOT EXISTS events_user ON events (user_id);
+
+    CREATE TABLE IF NOT EXISTS pending (
+        key TEXT PRIMARY KEY,
+        timestamp INTEGER,
+        event_id TEXT,
+        value BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS pending_timestamp ON pending (timestamp);
+
+    CREATE TABLE IF NOT EXISTS expiring (
+        key TEXT PRIMARY KEY,
+        timestamp INTEGER,
+        event_id TEXT,
+        value BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS expiring_timestamp ON expiring (timestamp);
+
+    CREATE TABLE IF NOT EXISTS records (
+        key TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+";
+
+// the key prefixes with their own table - other keys are records
+const TABLES: [(&str, &str); 4] = [("_u_", "users"), ("_v_", "events"), ("_p_", "pending"), ("_e_", "expiring")];
+
+// a store in a sqlite database - the tables can be inspected and queried with the standard sqlite tools
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    // open (or create) the database broker.sqlite3 in the save path
+    pub fn open(path: &str) -> Result<SqliteStore, String> {
+        std::fs::create_dir_all(path).map_err(|e| e.to_string())?;
+        let conn = Connection::open(std::path::Path::new(path).join("broker.sqlite3")).map_err(|e| e.to_string())?;
+        conn.pragma_update(None, "journal_mode", &"WAL").map_err(|e| e.to_string())?;
+        SqliteStore::init(conn)
+    }
+
+    pub fn temporary() -> Result<SqliteStore, String> {
+        let conn = Connection::open_in_memory().map_err(|e| e.to_string())?;
+        SqliteStore::init(conn)
+    }
+
+    fn init(conn: Connection) -> Result<SqliteStore, String> {
+        conn.execute_batch(SCHEMA).map_err(|e| e.to_string())?;
+        Ok(SqliteStore{conn: Mutex::new(conn)})
+    }
+}
+
+// the table of a key
+fn table(key: &str) -> &'static str {
+    TABLES.iter().find(|(prefix, _)| key.starts_with(prefix)).map(|(_, table)| *table).unwrap_or("records")
+}
+
+fn key_str(key: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(key).map_err(|e| e.to_string())
+}
+
+fn read(conn: &Connection, key: &str) -> rusqlite::Result<Option<Vec<u8>>> {
+    let sql = format!("SELECT value FROM {} WHERE key = ?1", table(key));
+    conn.query_row(&sql, params![key], |row| row.get(0)).optional()
+}
+
+fn delete(conn: &Connection, key: &str) -> rusqlite::Result<()> {
+    let sql = format!("DELETE FROM {} WHERE key = ?1", table(key));
+    conn.execute(&sql, params![key]).map(|_| ())
+}
+
+// write a key to its table - the columns are filled from the value when it decodes and the raw value is kept as is
+fn write(conn: &Connection, key: &str, value: &[u8]) -> rusqlite::Result<()> {
+    match table(key) {
+        "users" => {
+            let user : Option<User> = serde_json::from_slice(value).ok();
+            conn.execute("INSERT OR REPLACE INTO users (key, id, username, collection_id, tenant_id, value) VALUES (?1, ?2, ?3, ?4, ?5, ?6)", params![
+                key,
+                user.as_ref().map(|u| u.id.to_string()),
+                user.as_ref().map(|u| u.username.clone()),
+                user.as_ref().map(|u| u.collection_id.to_string()),
+                user.as_ref().map(|u| u.tenant_id.to_string()),
+                value
+            ])
+        },
+        "events" => {
+            let evt = try_decode_event(value).ok();
+            conn.execute("INSERT OR REPLACE INTO events (key, id, user_id, collection_id, tenant_id, event, timestamp, published, cancelled, expires_at, expired, data, value) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)", params![
+                key,
+                evt.as_ref().map(|e| e.id.to_string()),
+                evt.as_ref().map(|e| e.user_id.to_string()),
+                evt.as_ref().map(|e| e.collection_id.to_string()),
+                evt.as_ref().map(|e| e.tenant_id.to_string()),
+                evt.as_ref().map(|e| e.event.clone()),
+                evt.as_ref().map(|e| e.timestamp),
+                evt.as_ref().map(|e| e.published),
+                evt.as_ref().map(|e| e.cancelled),
+                evt.as_ref().and_then(|e| e.expires_at),
+                evt.as_ref().map(|e| e.expired),
+                evt.as_ref().map(|e| e.data.to_string()),
+                value
+            ])
+        },
+        // queue keys are {prefix}{timestamp:020}_{event id}
+        queue @ "pending" | queue @ "expiring" => {
+            let timestamp = key.get(3..23).and_then(|t| t.parse::<i64>().ok());
+            let event_id = key.get(24..);
+            let sql = format!("INSERT OR REPLACE INTO {} (key, timestamp, event_id, value) VALUES (?1, ?2, ?3, ?4)", queue);
+            conn.execute(&sql, params![key, timestamp, event_id, value])
+        },
+        _ => conn.execute("INSERT OR REPLACE INTO records (key, value) VALUES (?1, ?2)", params![key, value])
+    }.map(|_| ())
+}
+
+// the first key after all keys starting with a prefix - None when there is no such key
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.as_bytes().to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0x7f {
+            end.push(last + 1);
+            return String::from_utf8(end).ok()
+        }
+    }
+    None
+}
+
+fn scan(conn: &Connection, table: &str, prefix: &str) -> rusqlite::Result<Vec<Entry>> {
+    let map = |row: &rusqlite::Row| -> rusqlite::Result<Entry> {
+        let key : String = row.get(0)?;
+        Ok((key.into_bytes(), row.get(1)?))
+    };
+    match prefix_end(prefix) {
+        Some(end) => {
+            let mut stmt = conn.prepare(&format!("SELECT key, value FROM {} WHERE key >= ?1 AND key < ?2 ORDER BY key", table))?;
+            let rows = stmt.query_map(params![prefix, end], map)?;
+            rows.collect()
+        },
+        None => {
+            let mut stmt = conn.prepare(&format!("SELECT key, value FROM {} WHERE key >= ?1 ORDER BY key", table))?;
+            let rows = stmt.query_map(params![prefix], map)?;
+            rows.collect()
+        }
+    }
+}
+
+// the values of the rows of a table with a column value - looked up with the index of the column
+fn select(conn: &Connection, table: &str, column: &str, value: &str) -> rusqlite::Result<Vec<Vec<u8>>> {
+    let mut stmt = conn.prepare(&format!("SELECT value FROM {} WHERE {} = ?1 ORDER BY key", table, column))?;
+    let rows = stmt.query_map(params![value], |row| row.get(0))?;
+    rows.collect()
+}
+
+fn apply(tx: &Transaction, writes: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<(), String> {
+    for (k, v) in writes {
+        let key = key_str(&k)?;
+        match v {
+            Some(v) => write(tx, key, &v),
+            None => delete(tx, key)
+        }.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+impl Store for SqliteStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let conn = self.conn.lock().unwrap();
+        read(&conn, key_str(key)?).map_err(|e| e.to_string())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        write(&conn, key_str(key)?, value).map_err(|e| e.to_string())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), String> {
+        let conn = self.conn.lock().unwrap();
+        delete(&conn, key_str(key)?).map_err(|e| e.to_string())
+    }
+
+    fn compare_and_swap(&self, key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, String> {
+        let key = key_str(key)?;
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        let current = read(&tx, key).map_err(|e| e.to_string())?;
+        if current.as_deref() != old {
+            return Ok(false)
+        }
+        match new {
+            Some(new) => write(&tx, key, new),
+            None => delete(&tx, key)
+        }.map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+        apply(&tx, batch.writes)?;
+        tx.commit().map_err(|e| e.to_string())
+    }
+
+    // the entries are read out of every table the prefix can match and merged in key order
+    fn scan_prefix<'a>(&'a self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry, String>> + 'a> {
+        let prefix = match key_str(prefix) {
+            Ok(prefix) => prefix,
+            Err(e) => return Box::new(std::iter::once(Err(e)))
+        };
+        let mut tables : Vec<&str> = TABLES.iter().filter(|(p, _)| p.starts_with(prefix) || prefix.starts_with(p)).map(|(_, t)| *t).collect();
+        if !TABLES.iter().any(|(p, _)| prefix.starts_with(p)) {
+            tables.push("records");
+        }
+        let conn = self.conn.lock().unwrap();
+        let mut entries = Vec::new();
+        for t in tables {
+            match scan(&conn, t, prefix) {
+                Ok(rows) => entries.extend(rows),
+                Err(e) => return Box::new(std::iter::once(Err(e.to_string())))
+            }
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        Box::new(entries.into_iter().map(Ok))
+    }
+
+    // every write is committed by sqlite before it returns
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn users_named(&self, username: &str) -> Vec<User> {
+        let conn = self.conn.lock().unwrap();
+        select(&conn, "users", "username", username).unwrap().iter().map(|v| serde_json::from_slice(v).unwrap()).collect()
+    }
+
+    fn tenant_users(&self, tenant_id: uuid::Uuid) -> Vec<User> {
+        let conn = self.conn.lock().unwrap();
+        select(&conn, "users", "tenant_id", &tenant_id.to_string()).unwrap().iter().map(|v| serde_json::from_slice(v).unwrap()).collect()
+    }
+
+    fn tenant_events(&self, tenant_id: uuid::Uuid) -> Vec<Event> {
+        let conn = self.conn.lock().unwrap();
+        select(&conn, "events", "tenant_id", &tenant_id.to_string()).unwrap().iter().map(|v| decode_event(v)).collect()
+    }
+
+    fn collection_events(&self, collection_id: uuid::Uuid) -> Vec<Event> {
+        let conn = self.conn.lock().unwrap();
+        select(&conn, "events", "collection_id", &collection_id.to_string()).unwrap().iter().map(|v| decode_event(v)).collect()
+    }
+
+    fn user_events(&self, user_id: uuid::Uuid) -> Vec<Event> {
+        let conn = self.conn.lock().unwrap();
+        select(&conn, "events", "user_id", &user_id.to_string()).unwrap().iter().map(|v| decode_event(v)).collect()
+    }
+}
@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, RwLock};
+use crate::{User, Tenant, Event, IndexForm, decode_event, encode_event, field_value, index_entry_prefix, pending_key, expiry_key};
+
+// a key and value of the store
+pub type Entry = (Vec<u8>, Vec<u8>);
+
+// writes applied together by a store - a value of None removes the key
+#[derive(Debug, Default, Clone)]
+pub struct Batch {
+    pub(crate) writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.push((key.to_vec(), Some(value.to_vec())));
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        self.writes.push((key.to_vec(), None));
+    }
+}
+
+// persistence of the broker - a store is an ordered key value store and gets the users, events, indexes and pending queue on top of it
+pub trait Store: Send + Sync {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String>;
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), String>;
+    fn remove(&self, key: &[u8]) -> Result<(), String>;
+
+    // swap the value of a key if it still is the old value (None for a missing key) - returns whether it was swapped
+    fn compare_and_swap(&self, key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, String>;
+
+    // apply all the writes of a batch or none of them
+    fn apply_batch(&self, batch: Batch) -> Result<(), String>;
+
+    // the entries of the keys starting with a prefix in key order
+    fn scan_prefix<'a>(&'a self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry, String>> + 'a>;
+
+    // make the writes so far durable
+    fn flush(&self) -> Result<(), String>;
+
+    fn contains_key(&self, key: &[u8]) -> Result<bool, String> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = Result<Entry, String>> + 'a> {
+        self.scan_prefix(b"")
+    }
+
+    fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    // visit every entry as of one point in time - stops when visit returns false
+    // the default reads the entries with one scan so it is consistent for stores whose scans are (memory and sqlite)
+    fn snapshot(&self, visit: &mut dyn FnMut(Entry) -> bool) -> Result<(), String> {
+        for x in self.iter() {
+            if !visit(x?) {
+                break
+            }
+        }
+        Ok(())
+    }
+
+    // get a user by id
+    fn get_user(&self, user_id: &str) -> Option<User> {
+        let versioned = format!("_u_{}", user_id);
+        match self.get(versioned.as_bytes()).unwrap() {
+            Some(g) => Some(serde_json::from_slice(&g).unwrap()),
+            None => None
+        }
+    }
+
+    // get a tenant by id
+    fn get_tenant(&self, tenant_id: uuid::Uuid) -> Option<Tenant> {
+        let versioned = format!("_t_{}", tenant_id);
+        match self.get(versioned.as_bytes()).unwrap() {
+            Some(g) => Some(serde_json::from_slice(&g).unwrap()),
+            None => None
+        }
+    }
+
+    // get all tenants
+    fn tenants(&self) -> Vec<Tenant> {
+        self.scan_prefix(b"_t_").map(|x| {
+            let p = x.unwrap();
+            serde_json::from_slice(&p.1).unwrap()
+        }).collect()
+    }
+
+    // get an event by id
+    fn get_event(&self, event_id: &str) -> Option<Event> {
+        let versioned = format!("_v_{}", event_id);
+        match self.get(versioned.as_bytes()).unwrap() {
+            Some(g) => Some(decode_event(&g)),
+            None => None
+        }
+    }
+
+    // get the users with a username
+    fn users_named(&self, username: &str) -> Vec<User> {
+        self.scan_prefix(b"_u_").map(|x| {
+            let p = x.unwrap();
+            let user : User = serde_json::from_slice(&p.1).unwrap();
+            user
+        }).filter(|user| user.username == username).collect()
+    }
+
+    // get all users of a tenant
+    fn tenant_users(&self, tenant_id: uuid::Uuid) -> Vec<User> {
+        self.scan_prefix(b"_u_").map(|x| {
+            let p = x.unwrap();
+            let user : User = serde_json::from_slice(&p.1).unwrap();
+            user
+        }).filter(|user| user.tenant_id == tenant_id).collect()
+    }
+
+    // get all events of a tenant
+    fn tenant_events(&self, tenant_id: uuid::Uuid) -> Vec<Event> {
+        self.scan_prefix(b"_v_").map(|x| {
+            let p = x.unwrap();
+            decode_event(&p.1)
+        }).filter(|evt| evt.tenant_id == tenant_id).collect()
+    }
+
+    // get all events of a collection
+    fn collection_events(&self, collection_id: uuid::Uuid) -> Vec<Event> {
+        self.scan_prefix(b"_v_").map(|x| {
+            let p = x.unwrap();
+            decode_event(&p.1)
+        }).filter(|evt| evt.collection_id == collection_id).collect()
+    }
+
+    // get all events inserted by a user
+    fn user_events(&self, user_id: uuid::Uuid) -> Vec<Event> {
+        self.scan_prefix(b"_v_").map(|x| {
+            let p = x.unwrap();
+            decode_event(&p.1)
+        }).filter(|evt| evt.user_id == user_id).collect()
+    }
+
+    // replace a stored event if it still is the old event - compares decoded events so events of either encoding can be swapped
+    fn swap_event(&self, old: &Event, new: &Event) -> bool {
+        let versioned = format!("_v_{}", old.id);
+        let current = match self.get(versioned.as_bytes()).unwrap() {
+            Some(current) => current,
+            None => return false
+        };
+        if decode_event(&current) != *old {
+            return false
+        }
+        let encoded = encode_event(new);
+        let swapped = self.compare_and_swap(versioned.as_bytes(), Some(&current), Some(&encoded)).unwrap_or(false);
+        if swapped {
+            self.add_storage(new.tenant_id, encoded.len() as i64 - current.len() as i64);
+        }
+        swapped
+    }
+
+    // the bytes of the stored events of a tenant - a running count kept as events are written and deleted
+    fn storage_used(&self, tenant_id: uuid::Uuid) -> u64 {
+        match self.get(format!("_b_{}", tenant_id).as_bytes()).unwrap() {
+            Some(g) => std::str::from_utf8(&g).unwrap().parse::<u64>().unwrap(),
+            None => 0
+        }
+    }
+
+    // add bytes to (or take them off with a negative delta) the stored events of a tenant
+    fn add_storage(&self, tenant_id: uuid::Uuid, delta: i64) {
+        let key = format!("_b_{}", tenant_id);
+        let mut current = self.get(key.as_bytes()).unwrap();
+        loop {
+            let used = current.as_ref().map_or(0, |g| std::str::from_utf8(&g).unwrap().parse::<i64>().unwrap());
+            let updated = std::cmp::max(used + delta, 0).to_string();
+            if self.compare_and_swap(key.as_bytes(), current.as_deref(), Some(updated.as_bytes())).unwrap() {
+                return
+            }
+            current = self.get(key.as_bytes()).unwrap();
+        }
+    }
+
+    // get the indexed fields of a tenant
+    fn get_indexes(&self, tenant_id: uuid::Uuid) -> Vec<String> {
+        self.scan_prefix(format!("_n_{}_", tenant_id).as_bytes()).map(|x| {
+            let p = x.unwrap();
+            let index : IndexForm = serde_json::from_slice(&p.1).unwrap();
+            index.field
+        }).collect()
+    }
+
+    // keys of the index entries of an event for the indexed fields of its tenant
+    fn index_entries(&self, evt: &Event) -> Vec<String> {
+        self.get_indexes(evt.tenant_id).iter().filter_map(|field| {
+            field_value(evt, field).map(|value| format!("{}{}", index_entry_prefix(evt.tenant_id, field, &value), evt.id))
+        }).collect()
+    }
+
+    // keys of the pending queue, expiry queue and index entries of a new event
+    fn schedule_entries(&self, evt: &Event) -> Vec<String> {
+        let mut keys = vec![pending_key(evt)];
+        keys.extend(expiry_key(evt));
+        keys.extend(self.index_entries(evt));
+        keys
+    }
+
+    // add a new event to the pending queue, the expiry queue and the indexes
+    fn schedule_event(&self, evt: &Event) {
+        for key in self.schedule_entries(evt) {
+            let _ = self.insert(key.as_bytes(), evt.id.to_string().as_bytes());
+        }
+    }
+
+    // take the ids of the events due by now off the pending (_p_) or expiry (_e_) queue
+    fn take_due(&self, queue: &str, now: i64) -> Vec<String> {
+        let mut due = Vec::new();
+        for x in self.scan_prefix(queue.as_bytes()) {
+            let p = x.unwrap();
+            let k = std::str::from_utf8(&p.0).unwrap().to_owned();
+            let timestamp = k[3..23].parse::<i64>().unwrap();
+            if timestamp > now {
+                break
+            }
+            let _ = self.remove(&p.0);
+            due.push(std::str::from_utf8(&p.1).unwrap().to_owned());
+        }
+        due
+    }
+}
+
+// the default store - an embedded sled database
+// sled has no snapshots so the writes hold the read side of a lock and a snapshot holds the write side while it visits
+pub struct SledStore {
+    db: sled::Db,
+    writes: RwLock<()>,
+}
+
+impl SledStore {
+    pub fn open(path: &str) -> Result<SledStore, String> {
+        let db = sled::open(path).map_err(|e| e.to_string())?;
+        Ok(SledStore{db: db, writes: RwLock::new(())})
+    }
+
+    pub fn temporary() -> Result<SledStore, String> {
+        let db = sled::Config::new().temporary(true).open().map_err(|e| e.to_string())?;
+        Ok(SledStore{db: db, writes: RwLock::new(())})
+    }
+}
+
+impl Store for SledStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        self.db.get(key).map(|v| v.map(|v| v.to_vec())).map_err(|e| e.to_string())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        let _writing = self.writes.read().unwrap();
+        self.db.insert(key, value).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), String> {
+        let _writing = self.writes.read().unwrap();
+        self.db.remove(key).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn compare_and_swap(&self, key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, String> {
+        let _writing = self.writes.read().unwrap();
+        self.db.compare_and_swap(key, old, new).map(|swapped| swapped.is_ok()).map_err(|e| e.to_string())
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), String> {
+        let mut b = sled::Batch::default();
+        for (k, v) in batch.writes {
+            match v {
+                Some(v) => b.insert(k, v),
+                None => b.remove(k)
+            }
+        }
+        let _writing = self.writes.read().unwrap();
+        self.db.apply_batch(b).map_err(|e| e.to_string())
+    }
+
+    fn scan_prefix<'a>(&'a self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry, String>> + 'a> {
+        Box::new(self.db.scan_prefix(prefix).map(|x| x.map(|(k, v)| (k.to_vec(), v.to_vec())).map_err(|e| e.to_string())))
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        self.db.flush().map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn snapshot(&self, visit: &mut dyn FnMut(Entry) -> bool) -> Result<(), String> {
+        let _quiesced = self.writes.write().unwrap();
+        for x in self.db.iter() {
+            let (k, v) = x.map_err(|e| e.to_string())?;
+            if !visit((k.to_vec(), v.to_vec())) {
+                break
+            }
+        }
+        Ok(())
+    }
+}
+
+// a store kept in memory - for tests and ephemeral deployments (nothing survives a restart)
+#[derive(Default)]
+pub struct MemoryStore {
+    map: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        Ok(self.map.lock().unwrap().get(key).cloned())
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), String> {
+        self.map.lock().unwrap().insert(key.to_vec(), value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<(), String> {
+        self.map.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn compare_and_swap(&self, key: &[u8], old: Option<&[u8]>, new: Option<&[u8]>) -> Result<bool, String> {
+        let mut map = self.map.lock().unwrap();
+        if map.get(key).map(|v| v.as_slice()) != old {
+            return Ok(false)
+        }
+        match new {
+            Some(new) => map.insert(key.to_vec(), new.to_vec()),
+            None => map.remove(key)
+        };
+        Ok(true)
+    }
+
+    fn apply_batch(&self, batch: Batch) -> Result<(), String> {
+        let mut map = self.map.lock().unwrap();
+        for (k, v) in batch.writes {
+            match v {
+                Some(v) => map.insert(k, v),
+                None => map.remove(&k)
+            };
+        }
+        Ok(())
+    }
+
+    // the entries are copied out so writes while iterating don't deadlock
+    fn scan_prefix<'a>(&'a self, prefix: &[u8]) -> Box<dyn Iterator<Item = Result<Entry, String>> + 'a> {
+        let map = self.map.lock().unwrap();
+        let entries : Vec<Result<Entry, String>> = map.range(prefix.to_vec()..).take_while(|(k, _)| k.starts_with(prefix)).map(|(k, v)| Ok((k.clone(), v.clone()))).collect();
+        Box::new(entries.into_iter())
+    }
+
+    fn flush(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+extern crate broker;
+use serde_json::json;
+use std::process::Command;
+
+// run the broker binary with a command against the store at a save path
+fn broker_command(save_path: &std::path::Path, args: &[&str]) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_broker"))
+        .env("SAVE_PATH", save_path)
+        .args(args)
+        .status().unwrap()
+        .success()
+}
+
+// run against a running broker (admin secret admin) with: cargo test --test backup
+#[tokio::test]
+async fn backup_restore() {
+
+    let user = json!({"username": "backup1", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f92a", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd86440"});
+    let user_login = json!({"username": "backup1", "password": "rust"});
+
+    let client = reqwest::Client::new();
+
+    // create the tenant and the user (may already exist from an earlier run) and login
+    let _ = client.post("http://localhost:8080/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd86440", "name": "backup"}))
+        .send().await.unwrap();
+    let _ = client.post("http://localhost:8080/users")
+        .json(&user)
+        .send().await.unwrap();
+    let res = client.post("http://localhost:8080/login")
+        .json(&user_login)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let bearer = format!("Bearer {}", token.jwt);
+
+    // insert an event to back up
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "backed", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd86440", "collection_id": "3ca76743-8d99-4d3f-b85c-633ea456f92a", "timestamp": 1578667309, "data": {"backed": true}}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let record: broker::Record = serde_json::from_str(&res).unwrap();
+
+    // backup without the admin secret - want failure
+    let res = client.get("http://localhost:8080/admin/backup")
+        .header("Authorization", "Admin wrong")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 401);
+
+    // backup with the admin secret - want the user and the event in the snapshot
+    let res = client.get("http://localhost:8080/admin/backup")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let snapshot = res.text().await.unwrap();
+    assert!(snapshot.lines().all(|line| serde_json::from_str::<serde_json::Value>(line).is_ok()));
+    assert!(snapshot.lines().any(|line| line.contains(&record.event.id.to_string())));
+    assert!(snapshot.lines().any(|line| line.contains("\"backup1\"")));
+
+    // restore the snapshot into an empty store - want success
+    let dir = std::env::temp_dir().join(format!("broker_restore_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let snapshot_path = dir.join("backup.jsonl");
+    std::fs::write(&snapshot_path, &snapshot).unwrap();
+    let save_path = dir.join("data");
+    assert!(broker_command(&save_path, &["restore", snapshot_path.to_str().unwrap()]));
+
+    // restore into the now not empty store - want failure
+    assert!(!broker_command(&save_path, &["restore", snapshot_path.to_str().unwrap()]));
+
+    // backup the restored store - want the same snapshot
+    let restored_path = dir.join("restored.jsonl");
+    assert!(broker_command(&save_path, &["backup", restored_path.to_str().unwrap()]));
+    let restored = std::fs::read_to_string(&restored_path).unwrap();
+    assert_eq!(restored, snapshot);
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
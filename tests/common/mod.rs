@@ -0,0 +1,29 @@
+use std::process::{Command, Child};
+use std::time::Duration;
+
+// a broker started with flags - killed when dropped
+pub struct Broker(Child);
+
+impl Drop for Broker {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// start a broker with flags on the store at a save path (or the default one) and wait until it listens on the port
+pub async fn start_broker(client: &reqwest::Client, port: u16, save_path: Option<&std::path::Path>, args: &[&str]) -> Broker {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_broker"));
+    if let Some(save_path) = save_path {
+        command.env("SAVE_PATH", save_path);
+    }
+    let port_arg = port.to_string();
+    let broker = Broker(command.args(&["--port", &port_arg]).args(args).spawn().unwrap());
+    for _ in 0..100 {
+        if client.get(&format!("http://localhost:{}/", port)).send().await.is_ok() {
+            return broker
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+    panic!("broker did not listen on port {}", port)
+}
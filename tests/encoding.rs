@@ -0,0 +1,85 @@
+extern crate broker;
+use serde_json::json;
+
+mod common;
+
+#[tokio::test]
+async fn binary_encoded_store() {
+
+    // start a broker on a memory store writing binary events
+    let client = reqwest::Client::new();
+    let broker = common::start_broker(&client, 8093, None, &["--store", "memory", "--encoding", "binary", "--admin-secret", "admin"]).await;
+
+    let tenant = json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd86450", "name": "binary"});
+    let user1 = json!({"username": "binary1", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f93a", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd86450"});
+    let user2 = json!({"username": "binary2", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f93a", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd86450"});
+
+    // create the tenant - want success
+    let res = client.post("http://localhost:8093/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&tenant)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // create user 1 and login - want success
+    let res = client.post("http://localhost:8093/users")
+        .json(&user1)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.post("http://localhost:8093/login")
+        .json(&json!({"username": "binary1", "password": "rust"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let bearer = format!("Bearer {}", token.jwt);
+
+    // insert a binary encoded event - want success
+    let res = client.post("http://localhost:8093/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "binary", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd86450", "collection_id": "3ca76743-8d99-4d3f-b85c-633ea456f93a", "timestamp": 1578667309, "data": {"binary": true}}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let record: broker::Record = serde_json::from_str(&res).unwrap();
+
+    // create user 2 and try user 1 again with the event in the store - want success then failure
+    let res = client.post("http://localhost:8093/users")
+        .json(&user2)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.post("http://localhost:8093/users")
+        .json(&user1)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // login as user 2 - want success
+    let res = client.post("http://localhost:8093/login")
+        .json(&json!({"username": "binary2", "password": "rust"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // get the collection with HTTP Basic - want the event
+    let basic = format!("Basic {}", base64::encode("binary2:rust"));
+    let res = client.get("http://localhost:8093/collections/3ca76743-8d99-4d3f-b85c-633ea456f93a")
+        .header("Authorization", &basic)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let events : broker::Collection = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(events.events.len(), 1);
+    assert_eq!(events.events[0].id, record.event.id);
+
+    // get the events of user 1 - want the event
+    let res = client.get("http://localhost:8093/user_events")
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let events : serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(events["events"][0]["id"], json!(record.event.id));
+    assert_eq!(events["info"][0]["id"], json!(record.event.id));
+
+    drop(broker);
+}
@@ -0,0 +1,86 @@
+extern crate broker;
+use serde_json::json;
+use std::time::{Duration, Instant};
+
+// run against a running broker with: cargo test --test load -- --ignored
+#[tokio::test]
+#[ignore]
+async fn sse_latency_during_login_burst() {
+
+    let user = json!({"username": "load1", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f91c", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd8642b"});
+    let user_login = json!({"username": "load1", "password": "rust"});
+
+    let client = reqwest::Client::new();
+
+    // create the tenant and the user (may already exist from an earlier run) and login
+    let _ = client.post("http://localhost:8080/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd8642b", "name": "load"}))
+        .send().await.unwrap();
+    let _ = client.post("http://localhost:8080/users")
+        .json(&user)
+        .send().await.unwrap();
+    let res = client.post("http://localhost:8080/login")
+        .json(&user_login)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let bearer = format!("Bearer {}", token.jwt);
+
+    // measure the largest gap between sse chunks - the stream polls every 100ms
+    let measure = |bearer: String, period: Duration| async move {
+        let mut res = reqwest::Client::new().get("http://localhost:8080/events/e69d88c2-135e-4280-9cd8-d4a5edd8642b")
+            .header("Authorization", bearer)
+            .send().await.unwrap();
+        let started = Instant::now();
+        let mut last = Instant::now();
+        let mut max_gap = Duration::from_millis(0);
+        while started.elapsed() < period {
+            let chunk = res.chunk().await.unwrap();
+            assert!(chunk.is_some());
+            max_gap = std::cmp::max(max_gap, last.elapsed());
+            last = Instant::now();
+        }
+        max_gap
+    };
+
+    // baseline latency without load
+    let baseline = measure(bearer.clone(), Duration::from_secs(3)).await;
+
+    // latency while a burst of logins (bcrypt verify) runs
+    let burst = tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let logins : Vec<_> = (0..64).map(|_| {
+            client.post("http://localhost:8080/login")
+                .json(&user_login)
+                .send()
+        }).collect();
+        futures::future::join_all(logins).await
+    });
+    let loaded = measure(bearer.clone(), Duration::from_secs(3)).await;
+    for res in burst.await.unwrap() {
+        assert_eq!(res.unwrap().status(), 200);
+    }
+
+    // the stream should stay flat - allow some jitter over the baseline
+    assert!(loaded < baseline + Duration::from_millis(250), "baseline max gap {:?} - loaded max gap {:?}", baseline, loaded);
+
+    // latency while a burst of basic auth requests (user scan and bcrypt verify) runs
+    let basic = format!("Basic {}", base64::encode("load1:rust"));
+    let burst = tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let requests : Vec<_> = (0..64).map(|_| {
+            client.get("http://localhost:8080/collections/3ca76743-8d99-4d3f-b85c-633ea456f91c")
+                .header("Authorization", &basic)
+                .send()
+        }).collect();
+        futures::future::join_all(requests).await
+    });
+    let loaded = measure(bearer.clone(), Duration::from_secs(3)).await;
+    for res in burst.await.unwrap() {
+        assert_eq!(res.unwrap().status(), 200);
+    }
+
+    // the same allowance while basic auth requests run
+    assert!(loaded < baseline + Duration::from_millis(250), "baseline max gap {:?} - basic auth loaded max gap {:?}", baseline, loaded);
+}
@@ -0,0 +1,117 @@
+extern crate broker;
+use broker::{Store, SledStore};
+use serde_json::json;
+use std::process::Command;
+
+mod common;
+
+// write a version 1 store - no storage version, no tenant records and events without the expiry fields
+fn v1_fixture(save_path: &std::path::Path, user_id: uuid::Uuid, tenant_id: uuid::Uuid, collection_id: uuid::Uuid, event_ids: &[uuid::Uuid], timestamp: i64) {
+    let store = SledStore::open(save_path.to_str().unwrap()).unwrap();
+    let user = json!({"id": user_id, "username": "migrated1", "password": bcrypt::hash("rust", 4).unwrap(), "collection_id": collection_id, "tenant_id": tenant_id});
+    store.insert(format!("_u_{}", user_id).as_bytes(), user.to_string().as_bytes()).unwrap();
+    for event_id in event_ids {
+        let evt = json!({"id": event_id, "published": false, "cancelled": false, "data": {"v1": true}, "event": "migrated", "timestamp": timestamp, "user_id": user_id, "collection_id": collection_id, "tenant_id": tenant_id});
+        store.insert(format!("_v_{}", event_id).as_bytes(), evt.to_string().as_bytes()).unwrap();
+    }
+    store.flush().unwrap();
+}
+
+#[tokio::test]
+async fn migrate_v1_store() {
+
+    let user_id = uuid::Uuid::new_v4();
+    let tenant_id = uuid::Uuid::new_v4();
+    let collection_id = uuid::Uuid::new_v4();
+    let (cancelled_id, rescheduled_id) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+    let future = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() as i64 + 3600;
+
+    let dir = std::env::temp_dir().join(format!("broker_migrate_{}", uuid::Uuid::new_v4()));
+    v1_fixture(&dir, user_id, tenant_id, collection_id, &[cancelled_id, rescheduled_id], future);
+
+    // report the migrations without running them - want success
+    let status = Command::new(env!("CARGO_BIN_EXE_broker"))
+        .env("SAVE_PATH", &dir)
+        .args(&["--dry-run", "migrate"])
+        .status().unwrap();
+    assert!(status.success());
+
+    // start a broker on the store - it migrates on start
+    let client = reqwest::Client::new();
+    let broker = common::start_broker(&client, 8092, Some(&dir), &[]).await;
+
+    // login as the migrated user - want success
+    let res = client.post("http://localhost:8092/login")
+        .json(&json!({"username": "migrated1", "password": "rust"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let bearer = format!("Bearer {}", token.jwt);
+
+    // get the migrated collection - want both events with the expiry fields
+    let res = client.get(&format!("http://localhost:8092/collections/{}", collection_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let events : broker::Collection = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(events.events.len(), 2);
+    assert!(events.events.iter().all(|evt| evt.expires_at.is_none() && !evt.expired));
+
+    // cancel a migrated event - want success
+    let res = client.post(&format!("http://localhost:8092/cancel/{}", cancelled_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let event : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(event.event.cancelled, true);
+
+    // reschedule a migrated event - want success
+    let res = client.post(&format!("http://localhost:8092/reschedule/{}", rescheduled_id))
+        .header("Authorization", &bearer)
+        .json(&json!({"timestamp": future + 60}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let event : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(event.event.timestamp, future + 60);
+    assert_eq!(event.event.published, false);
+
+    drop(broker);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn migrate_retention_policy() {
+
+    let user_id = uuid::Uuid::new_v4();
+    let tenant_id = uuid::Uuid::new_v4();
+    let collection_id = uuid::Uuid::new_v4();
+    let dir = std::env::temp_dir().join(format!("broker_migrate_{}", uuid::Uuid::new_v4()));
+    v1_fixture(&dir, user_id, tenant_id, collection_id, &[uuid::Uuid::new_v4()], 1578667309);
+
+    // add a tenant record that already exists and a retention policy of the tenant in its old place
+    {
+        let store = SledStore::open(dir.to_str().unwrap()).unwrap();
+        let tenant = json!({"id": tenant_id, "name": "existing", "settings": {"origin": "https://example.com"}});
+        store.insert(format!("_t_{}", tenant_id).as_bytes(), tenant.to_string().as_bytes()).unwrap();
+        store.insert(format!("_l_{}", tenant_id).as_bytes(), json!({"max_age": 3600}).to_string().as_bytes()).unwrap();
+        store.flush().unwrap();
+    }
+
+    // migrate the store - want success
+    let status = Command::new(env!("CARGO_BIN_EXE_broker"))
+        .env("SAVE_PATH", &dir)
+        .arg("migrate")
+        .status().unwrap();
+    assert!(status.success());
+
+    // want the policy in the settings of the existing tenant, its other settings kept and the old policy gone
+    let store = SledStore::open(dir.to_str().unwrap()).unwrap();
+    let tenant : serde_json::Value = serde_json::from_slice(&store.get(format!("_t_{}", tenant_id).as_bytes()).unwrap().unwrap()).unwrap();
+    assert_eq!(tenant["name"], "existing");
+    assert_eq!(tenant["settings"]["origin"], "https://example.com");
+    assert_eq!(tenant["settings"]["retention"]["max_age"], 3600);
+    assert!(store.get(format!("_l_{}", tenant_id).as_bytes()).unwrap().is_none());
+
+    drop(store);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,87 @@
+extern crate broker;
+use serde_json::json;
+use std::time::Duration;
+
+mod common;
+
+#[tokio::test]
+async fn retention_prunes_and_archives() {
+
+    // start a broker on a memory store enforcing the retention policies every second
+    let client = reqwest::Client::new();
+    let archive_path = std::env::temp_dir().join(format!("broker_archive_{}", uuid::Uuid::new_v4()));
+    let broker = common::start_broker(&client, 8094, None, &["--store", "memory", "--admin-secret", "admin", "--retention-interval", "1", "--archive-path", archive_path.to_str().unwrap()]).await;
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86460";
+    let (collection_a, collection_b) = ("3ca76743-8d99-4d3f-b85c-633ea456f94a", "3ca76743-8d99-4d3f-b85c-633ea456f94b");
+
+    // create the tenant - want success
+    let res = client.post("http://localhost:8094/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": tenant_id, "name": "retention"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // create the user and login
+    let _ = client.post("http://localhost:8094/users")
+        .json(&json!({"username": "retention1", "password": "rust", "collection_id": collection_a, "tenant_id": tenant_id}))
+        .send().await.unwrap();
+    let res = client.post("http://localhost:8094/login")
+        .json(&json!({"username": "retention1", "password": "rust"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let bearer = format!("Bearer {}", token.jwt);
+
+    // insert due events - an old and a new "a" and a "b" in collection a and an "a" in collection b
+    let mut ids = Vec::new();
+    for (event, collection_id, timestamp) in vec![("a", collection_a, 1578667309), ("a", collection_a, 1578667310), ("b", collection_a, 1578667300), ("a", collection_b, 1578667300)] {
+        let res = client.post("http://localhost:8094/insert")
+            .header("Authorization", &bearer)
+            .json(&json!({"event": event, "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": timestamp, "data": {}}))
+            .send().await.unwrap()
+            .text().await.unwrap();
+        let record: broker::Record = serde_json::from_str(&res).unwrap();
+        ids.push(record.event.id);
+    }
+
+    // keep the latest event per collection and event name and archive the rest - want success
+    let res = client.post(&format!("http://localhost:8094/admin/retention/{}", tenant_id))
+        .header("Authorization", "Admin admin")
+        .json(&json!({"keep_latest": 1, "archive": true}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // wait for the retention task - want only the old "a" of collection a pruned
+    let collection = |collection_id: &'static str| {
+        let request = client.get(&format!("http://localhost:8094/collections/{}", collection_id)).header("Authorization", &bearer);
+        async move {
+            let events : broker::Collection = serde_json::from_str(&request.send().await.unwrap().text().await.unwrap()).unwrap();
+            events.events.iter().map(|evt| evt.id).collect::<Vec<uuid::Uuid>>()
+        }
+    };
+    let mut remaining = collection(collection_a).await;
+    for _ in 0..100 {
+        if remaining.len() < 3 {
+            break
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        remaining = collection(collection_a).await;
+    }
+    remaining.sort();
+    let mut kept = vec![ids[1], ids[2]];
+    kept.sort();
+    assert_eq!(remaining, kept);
+    assert_eq!(collection(collection_b).await, vec![ids[3]]);
+
+    // the pruned event is archived - want exactly the old "a"
+    let archived = std::fs::read_to_string(archive_path.join(format!("{}.jsonl", tenant_id))).unwrap();
+    let archived : Vec<broker::Event> = archived.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(archived.len(), 1);
+    assert_eq!(archived[0].id, ids[0]);
+
+    drop(broker);
+    let _ = std::fs::remove_dir_all(&archive_path);
+}
@@ -0,0 +1,102 @@
+extern crate broker;
+use broker::{Store, Batch, SledStore, MemoryStore, Event, encode_event_as};
+use serde_json::json;
+
+// the same checks against every store implementation
+fn check(store: &dyn Store) {
+
+    // insert, get and remove - want the value then nothing
+    store.insert(b"_u_1", b"one").unwrap();
+    assert_eq!(store.get(b"_u_1").unwrap(), Some(b"one".to_vec()));
+    assert_eq!(store.contains_key(b"_u_1").unwrap(), true);
+    store.remove(b"_u_1").unwrap();
+    assert_eq!(store.get(b"_u_1").unwrap(), None);
+
+    // compare and swap - want a swap only from the current value
+    assert_eq!(store.compare_and_swap(b"_v_1", None, Some(b"a")).unwrap(), true);
+    assert_eq!(store.compare_and_swap(b"_v_1", None, Some(b"b")).unwrap(), false);
+    assert_eq!(store.compare_and_swap(b"_v_1", Some(b"a"), Some(b"b")).unwrap(), true);
+    assert_eq!(store.get(b"_v_1").unwrap(), Some(b"b".to_vec()));
+
+    // batch - want all writes applied
+    let mut batch = Batch::default();
+    batch.insert(b"_v_2", b"c");
+    batch.insert(b"_v_3", b"d");
+    batch.remove(b"_v_1");
+    store.apply_batch(batch).unwrap();
+
+    // scan prefix - want the prefixed keys in order
+    store.insert(b"_w_1", b"e").unwrap();
+    let keys : Vec<Vec<u8>> = store.scan_prefix(b"_v_").map(|x| x.unwrap().0).collect();
+    assert_eq!(keys, vec![b"_v_2".to_vec(), b"_v_3".to_vec()]);
+    assert_eq!(store.iter().count(), 3);
+    assert_eq!(store.is_empty(), false);
+
+    // snapshot - want every entry until the visit stops
+    let mut seen = 0;
+    store.snapshot(&mut |_| { seen += 1; true }).unwrap();
+    assert_eq!(seen, 3);
+    let mut seen = 0;
+    store.snapshot(&mut |_| { seen += 1; false }).unwrap();
+    assert_eq!(seen, 1);
+
+    // take due - want the due ids taken off the queue in timestamp order
+    store.insert(format!("_p_{:020}_x", 10).as_bytes(), b"x").unwrap();
+    store.insert(format!("_p_{:020}_y", 5).as_bytes(), b"y").unwrap();
+    store.insert(format!("_p_{:020}_z", 20).as_bytes(), b"z").unwrap();
+    assert_eq!(store.take_due("_p_", 10), vec!["y".to_owned(), "x".to_owned()]);
+    assert_eq!(store.scan_prefix(b"_p_").count(), 1);
+    store.flush().unwrap();
+}
+
+// the same lookups of users and events against every store implementation
+fn check_lookups(store: &dyn Store) {
+    let (tenant_a, tenant_b) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+    let (collection_a, collection_b) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+    let (user_a, user_b) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+
+    // two users of different tenants
+    for (id, username, collection_id, tenant_id) in vec![(user_a, "a", collection_a, tenant_a), (user_b, "b", collection_b, tenant_b)] {
+        let user = json!({"id": id, "username": username, "password": "", "collection_id": collection_id, "tenant_id": tenant_id});
+        store.insert(format!("_u_{}", id).as_bytes(), user.to_string().as_bytes()).unwrap();
+    }
+
+    // three events (json and binary encoded) - two by user a in collection a and one by user b in collection b
+    for (i, (user_id, collection_id, tenant_id)) in vec![(user_a, collection_a, tenant_a), (user_a, collection_a, tenant_a), (user_b, collection_b, tenant_b)].into_iter().enumerate() {
+        let evt = Event{id: uuid::Uuid::new_v4(), user_id: user_id, collection_id: collection_id, tenant_id: tenant_id, event: "lookup".to_owned(), timestamp: i as i64, published: false, cancelled: false, data: json!({}), expires_at: None, expired: false};
+        store.insert(format!("_v_{}", evt.id).as_bytes(), &encode_event_as(&evt, i == 1)).unwrap();
+    }
+
+    // users by username and tenant - want only the matching users
+    let users = store.users_named("a");
+    assert_eq!(users.len(), 1);
+    assert_eq!(serde_json::to_value(&users[0]).unwrap()["id"], json!(user_a));
+    assert_eq!(store.users_named("c").len(), 0);
+    assert_eq!(store.tenant_users(tenant_b).len(), 1);
+
+    // events by tenant, collection and user - want only the matching events
+    assert_eq!(store.tenant_events(tenant_a).len(), 2);
+    assert_eq!(store.collection_events(collection_a).len(), 2);
+    assert!(store.collection_events(collection_b).iter().all(|evt| evt.user_id == user_b));
+    assert_eq!(store.user_events(user_b).len(), 1);
+    assert_eq!(store.user_events(uuid::Uuid::new_v4()).len(), 0);
+}
+
+#[test]
+fn sled_store() {
+    check(&SledStore::temporary().unwrap());
+    check_lookups(&SledStore::temporary().unwrap());
+}
+
+#[test]
+fn memory_store() {
+    check(&MemoryStore::default());
+    check_lookups(&MemoryStore::default());
+}
+
+#[cfg(feature = "sqlite")]
+#[test]
+fn sqlite_store() {
+    check(&broker::SqliteStore::temporary().unwrap());
+    check_lookups(&broker::SqliteStore::temporary().unwrap());
+}
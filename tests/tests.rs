@@ -1,6 +1,7 @@
 extern crate broker;
 use serde_json::json;
 use base64::encode;
+use json_patch::merge;
 
 #[tokio::test]
 async fn test1() {
@@ -18,6 +19,38 @@ async fn test1() {
     let basic_token = encode("rust22:rust");
     let basic = format!("Basic {}", basic_token);
 
+    // create a user of an unknown tenant - want failure
+    let res = client.post("http://localhost:8080/users")
+        .json(&json!({"username": "rust21", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f90c", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd8642c"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // create the tenants without the admin secret - want failure
+    let res = client.post("http://localhost:8080/admin/tenants")
+        .json(&json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd8642a", "name": "rust"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // create the tenants with the admin secret - want success
+    for tenant in vec![json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd8642a", "name": "rust"}), json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd8642f", "name": "imported"})] {
+        let res = client.post("http://localhost:8080/admin/tenants")
+            .header("Authorization", "Admin admin")
+            .json(&tenant)
+            .send().await.unwrap()
+            .status();
+        assert_eq!(res, 200);
+    }
+
+    // create a tenant again - want failure
+    let res = client.post("http://localhost:8080/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd8642a", "name": "rust"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
     // create user 1 - want success
     let res = client.post("http://localhost:8080/users")
         .json(&user1)
@@ -78,6 +111,7 @@ async fn test1() {
     assert_eq!(res.status(), 200);
     let event : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
     assert_eq!(event.event.published, false);
+    let published = event.clone();
 
     // post event with JWT - want success
     let res = client.post("http://localhost:8080/insert")
@@ -88,6 +122,26 @@ async fn test1() {
     let event2 : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
     assert_eq!(event2.event.published, false);
 
+    // post event with idempotency key - want success
+    let idempotency_key = uuid::Uuid::new_v4().to_string();
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .header("Idempotency-Key", &idempotency_key)
+        .json(&event1)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let first : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+
+    // retry event with same idempotency key - want original event
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .header("Idempotency-Key", &idempotency_key)
+        .json(&event1)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let retried : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(retried.event.id, first.event.id);
+
     // post event with HTTP Basic - want success
     let res = client.post("http://localhost:8080/insert")
         .header("Authorization", &basic)
@@ -97,6 +151,26 @@ async fn test1() {
     let event : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
     assert_eq!(event.event.published, false);
 
+    // post batch of events as newline delimited json - want success
+    let batch = format!("{}\n{}\n", event1.to_string(), event1.to_string());
+    let res = client.post("http://localhost:8080/insert/batch")
+        .header("Authorization", &bearer)
+        .body(batch)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let results : serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(results["results"].as_array().unwrap().len(), 2);
+    assert_eq!(results["results"][1]["event"]["published"], false);
+
+    // try posting atomic batch with an invalid event - want failure
+    let res = client.post("http://localhost:8080/insert/batch?atomic=true")
+        .header("Authorization", &bearer)
+        .json(&json!([event2, {"event": "test"}]))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 400);
+    let results : serde_json::Value = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(results["results"][0]["error"].is_string(), true);
+
     // try getting collection without auth - want failure
     let res = client.get("http://localhost:8080/collections/123")
         .send().await.unwrap()
@@ -123,6 +197,51 @@ async fn test1() {
     let events : broker::Collection = serde_json::from_str(&res.text().await.unwrap()).unwrap();
     assert_eq!(events.events[0].published, true);
 
+    // get first page of collection - want success
+    let res = client.get("http://localhost:8080/collections/3ca76743-8d99-4d3f-b85c-633ea456f90c?limit=1")
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let page : broker::Collection = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(page.events.len(), 1);
+    assert_eq!(page.next_cursor.is_some(), true);
+
+    // get next page of collection - want success
+    let url = format!("http://localhost:8080/collections/3ca76743-8d99-4d3f-b85c-633ea456f90c?limit=1&cursor={}", page.next_cursor.unwrap());
+    let res = client.get(&url)
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let next_page : broker::Collection = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(next_page.events.len(), 1);
+    assert_ne!(next_page.events[0].id, page.events[0].id);
+
+    // try getting collection with bad order - want failure
+    let res = client.get("http://localhost:8080/collections/3ca76743-8d99-4d3f-b85c-633ea456f90c?order=up")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // index event field - want success
+    let res = client.post("http://localhost:8080/indexes")
+        .header("Authorization", &bearer)
+        .json(&json!({"field": "event"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // query events by filter - want success
+    let filter = json!({"filter": {"and": [{"field": "event", "in": ["test"]}, {"field": "collection_id", "eq": "3ca76743-8d99-4d3f-b85c-633ea456f90c"}]}});
+    let res = client.post("http://localhost:8080/query")
+        .header("Authorization", &bearer)
+        .json(&filter)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let events : broker::Collection = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(events.events.len() >= 2, true);
+    assert_eq!(events.events.iter().all(|e| e.event == "test"), true);
+
     // try getting user without auth - want failure
     let res = client.get("http://localhost:8080/user_events")
         .send().await.unwrap()
@@ -136,15 +255,35 @@ async fn test1() {
         .status();
     assert_eq!(res, 200);
 
+    // reschedule future event with JWT - want success
+    let url = format!("http://localhost:8080/reschedule/{}", event2.event.id);
+    let res = client.post(&url)
+        .header("Authorization", &bearer)
+        .json(&json!({"timestamp": x + 1000}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let rescheduled : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(rescheduled.event.id, event2.event.id);
+    assert_eq!(rescheduled.event.timestamp, x + 1000);
+
+    // try rescheduling published event - want failure
+    let url = format!("http://localhost:8080/reschedule/{}", event.event.id);
+    let res = client.post(&url)
+        .header("Authorization", &bearer)
+        .json(&json!({"timestamp": x}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
     // try cancelling without auth - want failure
-    let res = client.get("http://localhost:8080/cancel/123")
+    let res = client.post("http://localhost:8080/cancel/123")
         .send().await.unwrap()
         .status();
     assert_eq!(res, 400);
 
     // cancel with JWT - want success
     let url = format!("http://localhost:8080/cancel/{}", event2.event.id);
-    let res = client.get(&url)
+    let res = client.post(&url)
         .header("Authorization", &bearer)
         .send().await.unwrap();
     assert_eq!(res.status(), 200);
@@ -153,7 +292,7 @@ async fn test1() {
 
     // cancel with HTTP Basic - want success
     let url = format!("http://localhost:8080/cancel/{}", event2.event.id);
-    let res = client.get(&url)
+    let res = client.delete(&url)
         .header("Authorization", &basic)
         .send().await.unwrap();
     assert_eq!(res.status(), 200);
@@ -175,4 +314,831 @@ async fn test1() {
     assert_eq!(res.status(), 200);
     let events : broker::Collection = serde_json::from_str(&res.text().await.unwrap()).unwrap();
     assert_eq!(events.events[0].published, false);
+
+    // try cancelling published event - want failure
+    let url = format!("http://localhost:8080/cancel/{}", published.event.id);
+    let res = client.post(&url)
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // retract published event - want success
+    let url = format!("http://localhost:8080/retract/{}", published.event.id);
+    let res = client.post(&url)
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let event : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(event.event.cancelled, true);
+
+    // backup without the admin secret - want failure
+    let res = client.get("http://localhost:8080/admin/backup")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 401);
+
+    // backup with the admin secret - want success
+    let res = client.get("http://localhost:8080/admin/backup")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let backup = res.text().await.unwrap();
+    assert!(backup.lines().any(|line| line.contains(&event.event.id.to_string())));
+
+    // export the tenant's events of a collection - want success
+    let res = client.get("http://localhost:8080/admin/export/e69d88c2-135e-4280-9cd8-d4a5edd8642a?collection_id=3ca76743-8d99-4d3f-b85c-633ea456f90c")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let export = res.text().await.unwrap();
+    assert!(export.lines().any(|line| line.contains(&event.event.id.to_string())));
+
+    // export and page the collection up to the event's timestamp - want the event from both as to is inclusive
+    let range = format!("from={}&to={}", event.event.timestamp, event.event.timestamp);
+    let res = client.get(&format!("http://localhost:8080/admin/export/e69d88c2-135e-4280-9cd8-d4a5edd8642a?collection_id=3ca76743-8d99-4d3f-b85c-633ea456f90c&{}", range))
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .text().await.unwrap();
+    assert!(res.lines().any(|line| line.contains(&event.event.id.to_string())));
+    let res = client.get(&format!("http://localhost:8080/collections/3ca76743-8d99-4d3f-b85c-633ea456f90c?{}", range))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    assert!(res.contains(&event.event.id.to_string()));
+
+    // import the export into another tenant with new ids - want success
+    let res = client.post("http://localhost:8080/admin/import?tenant_id=e69d88c2-135e-4280-9cd8-d4a5edd8642f&keep_ids=false")
+        .header("Authorization", "Admin admin")
+        .body(export.clone())
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    // import the export again keeping the ids - want failure
+    let res = client.post("http://localhost:8080/admin/import")
+        .header("Authorization", "Admin admin")
+        .body(export)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // set a retention policy of zero events - want failure
+    let res = client.post("http://localhost:8080/admin/retention/e69d88c2-135e-4280-9cd8-d4a5edd8642f")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"max_events": 0}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // set a retention policy archiving events older than a day - want success
+    let res = client.post("http://localhost:8080/admin/retention/e69d88c2-135e-4280-9cd8-d4a5edd8642f")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"max_age": 86400, "archive": true}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    // get the retention policy - want the max age
+    let res = client.get("http://localhost:8080/admin/retention/e69d88c2-135e-4280-9cd8-d4a5edd8642f")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .text().await.unwrap();
+    assert!(res.contains("86400"));
+
+    // remove a tenant with users - want failure
+    let res = client.delete("http://localhost:8080/admin/tenants/e69d88c2-135e-4280-9cd8-d4a5edd8642a")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // limit the payload of the tenant's events - want success
+    let res = client.post("http://localhost:8080/admin/tenants/e69d88c2-135e-4280-9cd8-d4a5edd8642a")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"settings": {"limits": {"max_payload_bytes": 8}}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // insert an event over the payload quota - want failure
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "test", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd8642a", "collection_id": "3ca76743-8d99-4d3f-b85c-633ea456f90c", "timestamp": x, "data": {"too": "large"}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 429);
+
+    // get the usage of the tenant - want the stored events
+    let res = client.get("http://localhost:8080/admin/tenants/e69d88c2-135e-4280-9cd8-d4a5edd8642a/usage")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let usage : broker::Usage = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert!(usage.events > 0);
+
+    // purge a tenant - want the purge started
+    let res = client.post("http://localhost:8080/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd8642d", "name": "purged"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.post("http://localhost:8080/users")
+        .json(&json!({"username": "rust25", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f90f", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd8642d"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.post("http://localhost:8080/login")
+        .json(&json!({"username": "rust25", "password": "rust"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let purged_bearer = format!("Bearer {}", token.jwt);
+    let res = client.post("http://localhost:8080/admin/tenants/e69d88c2-135e-4280-9cd8-d4a5edd8642d/purge")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 202);
+
+    // get the progress of the purge - want success
+    let res = client.get("http://localhost:8080/admin/tenants/e69d88c2-135e-4280-9cd8-d4a5edd8642d/purge")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let purge : broker::Purge = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(purge.tenant_id.to_string(), "e69d88c2-135e-4280-9cd8-d4a5edd8642d");
+
+    // use the token of a purged user once the purge is done - want failure
+    let mut status = purge.status;
+    for _ in 0..100 {
+        if status == "done" {
+            break
+        }
+        tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+        let res = client.get("http://localhost:8080/admin/tenants/e69d88c2-135e-4280-9cd8-d4a5edd8642d/purge")
+            .header("Authorization", "Admin admin")
+            .send().await.unwrap();
+        status = serde_json::from_str::<broker::Purge>(&res.text().await.unwrap()).unwrap().status;
+    }
+    assert_eq!(status, "done");
+    let res = client.get("http://localhost:8080/user_events")
+        .header("Authorization", &purged_bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 401);
+
+    // export a user for a privacy request - want the user without the password hash
+    let res = client.post("http://localhost:8080/users")
+        .json(&json!({"username": "rust24", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f90e", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd8642a"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let created : serde_json::Value = serde_json::from_str(&res).unwrap();
+    let user_id = created["id"].as_str().unwrap().to_owned();
+    let res = client.get(&format!("http://localhost:8080/admin/users/{}/export", user_id))
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let export : broker::UserExport = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(export.user.username, "rust24");
+
+    // erase the user without choosing what happens to its events - want failure
+    let res = client.delete(&format!("http://localhost:8080/admin/users/{}", user_id))
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // erase the user deleting its events - want success
+    let res = client.delete(&format!("http://localhost:8080/admin/users/{}?events=delete", user_id))
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // erase a user with a recurrence keeping its events - want success
+    let res = client.post("http://localhost:8080/users")
+        .json(&json!({"username": "rust27", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f90e", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd8642a"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let created : serde_json::Value = serde_json::from_str(&res).unwrap();
+    let user_id = created["id"].as_str().unwrap().to_owned();
+    let res = client.post("http://localhost:8080/login")
+        .json(&json!({"username": "rust27", "password": "rust"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let erased_bearer = format!("Bearer {}", token.jwt);
+    let res = client.post("http://localhost:8080/recurring")
+        .header("Authorization", &erased_bearer)
+        .json(&json!({"event": "erased", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd8642a", "collection_id": "3ca76743-8d99-4d3f-b85c-633ea456f90e", "data": {}, "frequency": "daily", "starts_at": x + 86400}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let recurrence : broker::Recurrence = serde_json::from_str(&res).unwrap();
+    let res = client.delete(&format!("http://localhost:8080/admin/users/{}?events=anonymize", user_id))
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // use the token of the erased user - want failure
+    let res = client.get("http://localhost:8080/user_events")
+        .header("Authorization", &erased_bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 401);
+
+    // get the pending occurrence of the erased user's recurrence - want it cancelled
+    let res = client.get("http://localhost:8080/collections/3ca76743-8d99-4d3f-b85c-633ea456f90e")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let events : broker::Collection = serde_json::from_str(&res).unwrap();
+    let occurrence = events.events.iter().find(|evt| Some(evt.id) == recurrence.next_event_id).unwrap();
+    assert_eq!(occurrence.cancelled, true);
+}
+
+// create a tenant and a user of it (both may already exist from an earlier run) and login - returns the bearer header
+async fn login_tenant(client: &reqwest::Client, tenant_id: &str, username: &str, collection_id: &str) -> String {
+    let _ = client.post("http://localhost:8080/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": tenant_id, "name": username}))
+        .send().await.unwrap();
+    let _ = client.post("http://localhost:8080/users")
+        .json(&json!({"username": username, "password": "rust", "collection_id": collection_id, "tenant_id": tenant_id}))
+        .send().await.unwrap();
+    let res = client.post("http://localhost:8080/login")
+        .json(&json!({"username": username, "password": "rust"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    format!("Bearer {}", token.jwt)
+}
+
+// read a sse stream until an event with the name whose data passes the check arrives (at most 10 seconds) - returns its data
+async fn next_sse(res: &mut reqwest::Response, buffer: &mut String, name: &str, check: &dyn Fn(&serde_json::Value) -> bool) -> serde_json::Value {
+    let started = std::time::Instant::now();
+    loop {
+        while let Some(end) = buffer.find("\n\n") {
+            let block : String = buffer.drain(..end + 2).collect();
+            let event = block.lines().find(|line| line.starts_with("event:")).map(|line| line[6..].to_owned());
+            let data = block.lines().find(|line| line.starts_with("data:")).map(|line| line[5..].to_owned());
+            if let (Some(event), Some(data)) = (event, data) {
+                if event == name {
+                    let value : serde_json::Value = serde_json::from_str(&data).unwrap();
+                    if check(&value) {
+                        return value
+                    }
+                }
+            }
+        }
+        assert!(started.elapsed() < std::time::Duration::from_secs(10), "no {} event", name);
+        let chunk = res.chunk().await.unwrap().unwrap();
+        buffer.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+}
+
+#[tokio::test]
+async fn columns() {
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86431";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f931";
+    let client = reqwest::Client::new();
+    let bearer = login_tenant(&client, tenant_id, "rust26", collection_id).await;
+
+    // insert a published event with data to show in the grid - want success
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "grid", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": 1578667309, "data": {"name": "a", "count": 1}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    // the snapshot without a column definition - want the columns inferred with their types
+    let mut res = client.get(&format!("http://localhost:8080/events/{}", tenant_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    let mut buffer = String::new();
+    let snapshot = next_sse(&mut res, &mut buffer, "grid", &|_| true).await;
+    let fields : Vec<&str> = snapshot["columns"].as_array().unwrap().iter().map(|c| c["field"].as_str().unwrap()).collect();
+    assert_eq!(fields, vec!["timestamp", "collection_id", "count", "name"]);
+    let types : Vec<&str> = snapshot["columns"].as_array().unwrap().iter().map(|c| c["type"].as_str().unwrap()).collect();
+    assert_eq!(types, vec!["timestamp", "string", "number", "string"]);
+    drop(res);
+
+    // set a column definition - want success
+    let res = client.post("http://localhost:8080/columns")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "grid", "columns": [{"field": "name", "title": "Name", "type": "string"}, {"field": "count", "title": "Count", "type": "number", "hidden": true, "format": "0,0"}]}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // get the column definition - want the columns in order
+    let res = client.get("http://localhost:8080/columns/grid")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let columns : broker::Columns = serde_json::from_str(&res).unwrap();
+    assert_eq!(columns.columns.iter().map(|c| c.field.as_str()).collect::<Vec<&str>>(), vec!["name", "count"]);
+    assert_eq!(columns.columns[1].hidden, true);
+
+    // the snapshot with the column definition - want the defined columns
+    let mut res = client.get(&format!("http://localhost:8080/events/{}", tenant_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    let mut buffer = String::new();
+    let snapshot = next_sse(&mut res, &mut buffer, "grid", &|_| true).await;
+    assert_eq!(snapshot["columns"][0]["title"], "Name");
+    assert_eq!(snapshot["columns"][1]["format"], "0,0");
+    assert_eq!(snapshot["columns"].as_array().unwrap().len(), 2);
+    assert_eq!(snapshot["rows"][0]["name"], "a");
+}
+
+#[tokio::test]
+async fn aggregate() {
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86432";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f932";
+    let client = reqwest::Client::new();
+    let bearer = login_tenant(&client, tenant_id, "rust29", collection_id).await;
+
+    // insert sales and a refund on the same day - want success
+    let day = 1578614400;
+    for (event, total) in vec![("sale", 10), ("sale", 5), ("refund", 1)] {
+        let res = client.post("http://localhost:8080/insert")
+            .header("Authorization", &bearer)
+            .json(&json!({"event": event, "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": day + 3600, "data": {"total": total}}))
+            .send().await.unwrap()
+            .status();
+        assert_eq!(res, 200);
+    }
+
+    // try aggregating by an unknown bucket - want failure
+    let res = client.post("http://localhost:8080/aggregate")
+        .header("Authorization", &bearer)
+        .json(&json!({"metrics": [{"op": "count"}], "bucket": "week"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // count and sum per event name and day - want the totals of each group in the day bucket
+    let res = client.post("http://localhost:8080/aggregate")
+        .header("Authorization", &bearer)
+        .json(&json!({"metrics": [{"op": "count"}, {"op": "sum", "field": "total", "name": "revenue"}, {"op": "max", "field": "total"}], "group_by": "event", "bucket": "day", "from": day, "to": day + 86400}))
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let aggregates : broker::Aggregates = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(aggregates.results.len(), 2);
+    let sales = aggregates.results.iter().find(|a| a.group == "sale").unwrap();
+    assert_eq!(sales.bucket, Some(day));
+    assert_eq!(sales.metrics["count"], 2);
+    assert_eq!(sales.metrics["revenue"], 15.0);
+    assert_eq!(sales.metrics["max(total)"], 10.0);
+    let refunds = aggregates.results.iter().find(|a| a.group == "refund").unwrap();
+    assert_eq!(refunds.metrics["count"], 1);
+}
+
+#[tokio::test]
+async fn state() {
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86433";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f933";
+    let other_collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f934";
+    let client = reqwest::Client::new();
+    let bearer = login_tenant(&client, tenant_id, "rust30", collection_id).await;
+
+    // insert two events of a collection and one of another - want success
+    let mut ids = Vec::new();
+    for (collection, timestamp) in vec![(collection_id, 1578667300), (collection_id, 1578667310), (other_collection_id, 1578667305)] {
+        let res = client.post("http://localhost:8080/insert")
+            .header("Authorization", &bearer)
+            .json(&json!({"event": "status", "tenant_id": tenant_id, "collection_id": collection, "timestamp": timestamp, "data": {"at": timestamp}}))
+            .send().await.unwrap()
+            .text().await.unwrap();
+        let record : broker::Record = serde_json::from_str(&res).unwrap();
+        ids.push(record.event.id);
+    }
+    std::thread::sleep(std::time::Duration::from_millis(500));
+
+    // get the state - want the latest event of each collection by ascending timestamp
+    let res = client.get("http://localhost:8080/state/status")
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let state : broker::Collection = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_eq!(state.events.iter().map(|e| e.id).collect::<Vec<uuid::Uuid>>(), vec![ids[2], ids[1]]);
+
+    // retract the latest event of the collection - want the event before it back in the state
+    let res = client.post(&format!("http://localhost:8080/retract/{}", ids[1]))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.get("http://localhost:8080/state/status")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let state : broker::Collection = serde_json::from_str(&res).unwrap();
+    assert_eq!(state.events.iter().map(|e| e.id).collect::<Vec<uuid::Uuid>>(), vec![ids[0], ids[2]]);
+
+    // get the state of an event name without events - want nothing
+    let res = client.get("http://localhost:8080/state/unknown")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let state : broker::Collection = serde_json::from_str(&res).unwrap();
+    assert_eq!(state.events.len(), 0);
+}
+
+#[tokio::test]
+async fn expiry() {
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86434";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f935";
+    let client = reqwest::Client::new();
+    let bearer = login_tenant(&client, tenant_id, "rust34", collection_id).await;
+    let now = broker::get_ntp_time();
+
+    // try inserting an event expiring before its timestamp - want failure
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "presence", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": now, "expires_at": now - 1, "data": {}}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    assert!(res.contains("error"));
+
+    // subscribe before the event is published
+    let mut stream = client.get(&format!("http://localhost:8080/events/{}", tenant_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap();
+    let mut buffer = String::new();
+
+    // insert an event that is published now and expires in 2 seconds - want success
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "presence", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": now, "ttl": 2, "data": {"online": true}}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let record : broker::Record = serde_json::from_str(&res).unwrap();
+    assert_eq!(record.event.expires_at, Some(now + 2));
+    let id = record.event.id.to_string();
+
+    // the published event is in the snapshot and the state - want the event
+    let snapshot = next_sse(&mut stream, &mut buffer, "presence", &|data| data["events"].as_array().unwrap().len() > 0).await;
+    assert_eq!(snapshot["events"][0]["id"], id.as_str());
+    let res = client.get("http://localhost:8080/state/presence")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let state : broker::Collection = serde_json::from_str(&res).unwrap();
+    assert_eq!(state.events.len(), 1);
+
+    // wait for the expiry - want an internal_expired event with the expired event
+    let expired = next_sse(&mut stream, &mut buffer, "internal_expired", &|data| data["event"]["id"] == id.as_str()).await;
+    assert_eq!(expired["event"]["expired"], true);
+
+    // the expired event left the state - want nothing
+    let res = client.get("http://localhost:8080/state/presence")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let state : broker::Collection = serde_json::from_str(&res).unwrap();
+    assert_eq!(state.events.len(), 0);
+}
+
+#[tokio::test]
+async fn recurring() {
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86435";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f936";
+    let client = reqwest::Client::new();
+    let bearer = login_tenant(&client, tenant_id, "rust33", collection_id).await;
+    let template = json!({"event": "reminder", "tenant_id": tenant_id, "collection_id": collection_id, "data": {"text": "hi"}});
+
+    // try creating templates with an unknown frequency, a bad cron and an unknown time zone - want failure
+    for schedule in vec![json!({"frequency": "yearly"}), json!({"cron": "every day"}), json!({"frequency": "daily", "timezone": "Mars/Olympus"})] {
+        let mut form = template.clone();
+        merge(&mut form, &schedule);
+        let res = client.post("http://localhost:8080/recurring")
+            .header("Authorization", &bearer)
+            .json(&form)
+            .send().await.unwrap()
+            .status();
+        assert_eq!(res, 400);
+    }
+
+    // a cron template every second for 3 occurrences - want the occurrences published one after the other
+    let mut form = template.clone();
+    merge(&mut form, &json!({"cron": "* * * * * *", "count": 3}));
+    let res = client.post("http://localhost:8080/recurring")
+        .header("Authorization", &bearer)
+        .json(&form)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let recurrence : broker::Recurrence = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert!(recurrence.next_event_id.is_some());
+    std::thread::sleep(std::time::Duration::from_secs(5));
+    let res = client.get("http://localhost:8080/recurring")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let recurrences : broker::Recurrences = serde_json::from_str(&res).unwrap();
+    let finished = recurrences.recurring.iter().find(|r| r.id == recurrence.id).unwrap();
+    assert_eq!(finished.occurrences, 3);
+    assert_eq!(finished.next_at, None);
+    let res = client.get(&format!("http://localhost:8080/collections/{}?event=reminder", collection_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let events : broker::Collection = serde_json::from_str(&res).unwrap();
+    assert_eq!(events.events.iter().filter(|e| e.published).count(), 3);
+
+    // daily at 9am in new york across the start of daylight saving time - want 9am local on both days (23 hours apart)
+    let mut next = Vec::new();
+    for starts_at in vec![1899262800, 1899295201] {
+        let mut form = template.clone();
+        merge(&mut form, &json!({"cron": "0 0 9 * * *", "timezone": "America/New_York", "starts_at": starts_at}));
+        let res = client.post("http://localhost:8080/recurring")
+            .header("Authorization", &bearer)
+            .json(&form)
+            .send().await.unwrap()
+            .text().await.unwrap();
+        let recurrence : broker::Recurrence = serde_json::from_str(&res).unwrap();
+        next.push(recurrence);
+    }
+    assert_eq!(next[0].next_at, Some(1899295200));
+    assert_eq!(next[1].next_at, Some(1899378000));
+
+    // pause a template - want its next occurrence cancelled
+    let daily = next[0].clone();
+    let res = client.post(&format!("http://localhost:8080/recurring/{}/pause", daily.id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let paused : broker::Recurrence = serde_json::from_str(&res).unwrap();
+    assert_eq!(paused.paused, true);
+    assert_eq!(paused.next_event_id, None);
+    let res = client.get(&format!("http://localhost:8080/collections/{}?event=reminder", collection_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let events : broker::Collection = serde_json::from_str(&res).unwrap();
+    assert_eq!(events.events.iter().find(|e| e.id == daily.next_event_id.unwrap()).unwrap().cancelled, true);
+
+    // resume the template - want a new next occurrence
+    let res = client.post(&format!("http://localhost:8080/recurring/{}/resume", daily.id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let resumed : broker::Recurrence = serde_json::from_str(&res).unwrap();
+    assert_eq!(resumed.paused, false);
+    assert!(resumed.next_event_id.is_some());
+    assert_ne!(resumed.next_event_id, daily.next_event_id);
+
+    // delete the templates - want success
+    for recurrence in next {
+        let res = client.delete(&format!("http://localhost:8080/recurring/{}", recurrence.id))
+            .header("Authorization", &bearer)
+            .send().await.unwrap()
+            .status();
+        assert_eq!(res, 200);
+    }
+}
+
+#[tokio::test]
+async fn idempotency() {
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86436";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f937";
+    let client = reqwest::Client::new();
+    let bearer = login_tenant(&client, tenant_id, "rust36", collection_id).await;
+    let event = json!({"event": "order", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": 1578667309, "data": {}});
+
+    // send the same request concurrently - want one event for all the accepted requests
+    let key = uuid::Uuid::new_v4().to_string();
+    let requests : Vec<_> = (0..8).map(|_| {
+        client.post("http://localhost:8080/insert")
+            .header("Authorization", &bearer)
+            .header("Idempotency-Key", &key)
+            .json(&event)
+            .send()
+    }).collect();
+    let mut ids = std::collections::HashSet::new();
+    for res in futures::future::join_all(requests).await {
+        let res = res.unwrap();
+        if res.status() == 200 {
+            let record : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+            ids.insert(record.event.id);
+        } else {
+            assert_eq!(res.status(), 409);
+        }
+    }
+    assert_eq!(ids.len(), 1);
+    let res = client.get(&format!("http://localhost:8080/collections/{}", collection_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let events : broker::Collection = serde_json::from_str(&res).unwrap();
+    assert_eq!(events.events.len(), 1);
+
+    // limit the tenant to the events it already has today - want success
+    let res = client.post(&format!("http://localhost:8080/admin/tenants/{}", tenant_id))
+        .header("Authorization", "Admin admin")
+        .json(&json!({"settings": {"limits": {"events_per_day": 1}}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // retry the accepted request - want the original event instead of a 429
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .header("Idempotency-Key", &key)
+        .json(&event)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let record : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert!(ids.contains(&record.event.id));
+
+    // insert a new event over the quota - want failure
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .header("Idempotency-Key", uuid::Uuid::new_v4().to_string())
+        .json(&event)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 429);
+}
+
+#[tokio::test]
+async fn idempotency_deleted_event() {
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86439";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f939";
+    let client = reqwest::Client::new();
+    let erased_bearer = login_tenant(&client, tenant_id, "rust39", collection_id).await;
+    let bearer = login_tenant(&client, tenant_id, "rust40", collection_id).await;
+    let event = json!({"event": "order", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": 1578667309, "data": {}});
+
+    // insert an event with a key and get the user that inserted it - want success
+    let key = uuid::Uuid::new_v4().to_string();
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &erased_bearer)
+        .header("Idempotency-Key", &key)
+        .json(&event)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let original : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+
+    // delete the event by erasing its user - want success
+    let res = client.delete(&format!("http://localhost:8080/admin/users/{}?events=delete", original.event.user_id))
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // replay the key once the claim is past its grace period - want a new event instead of a 409
+    tokio::time::delay_for(std::time::Duration::from_secs(6)).await;
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .header("Idempotency-Key", &key)
+        .json(&event)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let replayed : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    assert_ne!(replayed.event.id, original.event.id);
+}
+
+#[tokio::test]
+async fn quotas() {
+
+    let client = reqwest::Client::new();
+    let tenant_id = uuid::Uuid::new_v4().to_string();
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f947";
+    let bearer = login_tenant(&client, &tenant_id, &format!("rust47-{}", tenant_id), collection_id).await;
+    let event = json!({"event": "quota", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": 1578667309, "data": {"quota": true}});
+
+    // limit the tenant to 5 events per day - want success
+    let res = client.post(&format!("http://localhost:8080/admin/tenants/{}", tenant_id))
+        .header("Authorization", "Admin admin")
+        .json(&json!({"settings": {"limits": {"events_per_day": 5}}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // insert 10 events at once - want exactly 5 inserted and the rest over the quota
+    let inserts : Vec<_> = (0..10).map(|_| {
+        client.post("http://localhost:8080/insert")
+            .header("Authorization", &bearer)
+            .json(&event)
+            .send()
+    }).collect();
+    let mut inserted = Vec::new();
+    for res in futures::future::join_all(inserts).await {
+        let res = res.unwrap();
+        if res.status() == 200 {
+            let record : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+            inserted.push(record.event);
+        } else {
+            assert_eq!(res.status(), 429);
+        }
+    }
+    assert_eq!(inserted.len(), 5);
+
+    // get the usage of the tenant - want the counted events and the bytes of the stored events
+    let usage = |client: reqwest::Client, tenant_id: String| async move {
+        let res = client.get(&format!("http://localhost:8080/admin/tenants/{}/usage", tenant_id))
+            .header("Authorization", "Admin admin")
+            .send().await.unwrap();
+        let usage : broker::Usage = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+        usage
+    };
+    let current = usage(client.clone(), tenant_id.clone()).await;
+    assert_eq!(current.events_today, 5);
+    assert_eq!(current.events, 5);
+
+    // wait for the events to be published - the stored bytes follow the published events
+    tokio::time::delay_for(std::time::Duration::from_secs(2)).await;
+    let res = client.get(&format!("http://localhost:8080/collections/{}", collection_id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let events : broker::Collection = serde_json::from_str(&res).unwrap();
+    assert_eq!(events.events.len(), 5);
+    let bytes : u64 = events.events.iter().map(|evt| serde_json::to_string(evt).unwrap().len() as u64).sum();
+    let current = usage(client.clone(), tenant_id.clone()).await;
+    assert_eq!(current.storage_bytes, bytes);
+
+    // limit the storage to the stored bytes and lift the events per day - want a new event over the storage quota
+    let res = client.post(&format!("http://localhost:8080/admin/tenants/{}", tenant_id))
+        .header("Authorization", "Admin admin")
+        .json(&json!({"settings": {"limits": {"events_per_day": null, "storage_bytes": bytes}}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .json(&event)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 429);
+
+    // create a recurring template at the storage quota - want its first occurrence over the quota
+    let res = client.post("http://localhost:8080/recurring")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "quota", "tenant_id": tenant_id, "collection_id": collection_id, "data": {"quota": true}, "frequency": "daily"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 429);
+
+    // lift the storage quota and limit the payload - want a future event rescheduled with larger data over the quota
+    let res = client.post(&format!("http://localhost:8080/admin/tenants/{}", tenant_id))
+        .header("Authorization", "Admin admin")
+        .json(&json!({"settings": {"limits": {"storage_bytes": null, "max_payload_bytes": 20}}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let timestamp = broker::get_ntp_time() + 1000;
+    let mut future = event.clone();
+    merge(&mut future, &json!({"timestamp": timestamp}));
+    let res = client.post("http://localhost:8080/insert")
+        .header("Authorization", &bearer)
+        .json(&future)
+        .send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    let record : broker::Record = serde_json::from_str(&res.text().await.unwrap()).unwrap();
+    let url = format!("http://localhost:8080/reschedule/{}", record.event.id);
+    let res = client.post(&url)
+        .header("Authorization", &bearer)
+        .json(&json!({"timestamp": timestamp + 10, "data": {"quota": "a payload larger than the limit"}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 429);
+
+    // reschedule with data under the limit - want success
+    let res = client.post(&url)
+        .header("Authorization", &bearer)
+        .json(&json!({"timestamp": timestamp + 10, "data": {"quota": false}}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+}
+
+#[tokio::test]
+async fn webhooks() {
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86438";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f938";
+    let client = reqwest::Client::new();
+    let bearer = login_tenant(&client, tenant_id, "rust38", collection_id).await;
+
+    // create webhooks to loopback, private and link-local addresses - want failure
+    for url in vec!["http://127.0.0.1:8091/hook", "http://localhost/hook", "http://10.0.0.1/hook", "http://169.254.169.254/latest/meta-data", "http://[::1]/hook", "http://[::ffff:192.168.0.1]/hook"] {
+        let res = client.post("http://localhost:8080/webhooks")
+            .header("Authorization", &bearer)
+            .json(&json!({"url": url}))
+            .send().await.unwrap()
+            .status();
+        assert_eq!(res, 400);
+    }
 }
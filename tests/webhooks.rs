@@ -0,0 +1,438 @@
+extern crate broker;
+use serde_json::json;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::Duration;
+use warp::Filter;
+
+mod common;
+
+// local http stub on a port that fails every post and hands its body to the test
+fn failing_stub(port: u16) -> tokio::sync::mpsc::UnboundedReceiver<String> {
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let stub = warp::post()
+        .and(warp::path("failing"))
+        .and(warp::body::bytes())
+        .map(move |body: warp::hyper::body::Bytes| {
+            let _ = tx.send(String::from_utf8(body.to_vec()).unwrap());
+            warp::reply::with_status("failed", warp::http::StatusCode::INTERNAL_SERVER_ERROR)
+        });
+    tokio::spawn(warp::serve(stub).run(([127, 0, 0, 1], port)));
+    rx
+}
+
+#[tokio::test]
+async fn webhook_delivery() {
+
+    // start a broker on a memory store that may post to the local stub
+    let client = reqwest::Client::new();
+    let broker = common::start_broker(&client, 8095, None, &["--store", "memory", "--admin-secret", "admin", "--webhook-allow-private"]).await;
+
+    let user = json!({"username": "hook1", "password": "rust", "collection_id":"3ca76743-8d99-4d3f-b85c-633ea456f91d", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd86430"});
+    let user_login = json!({"username": "hook1", "password": "rust"});
+
+    // local http stub that hands every post to the test
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let stub = warp::post()
+        .and(warp::path("hook"))
+        .and(warp::header::<String>("host"))
+        .and(warp::header::<String>("x-broker-signature"))
+        .and(warp::body::bytes())
+        .map(move |host: String, signature: String, body: warp::hyper::body::Bytes| {
+            let _ = tx.send((host, signature, String::from_utf8(body.to_vec()).unwrap()));
+            "ok"
+        });
+    tokio::spawn(warp::serve(stub).run(([127, 0, 0, 1], 8091)));
+
+    // create the tenant, the user and login
+    let res = client.post("http://localhost:8095/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": "e69d88c2-135e-4280-9cd8-d4a5edd86430", "name": "hooks"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let _ = client.post("http://localhost:8095/users")
+        .json(&user)
+        .send().await.unwrap();
+    let res = client.post("http://localhost:8095/login")
+        .json(&user_login)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let bearer = format!("Bearer {}", token.jwt);
+
+    // create a webhook with a bad url - want failure
+    let res = client.post("http://localhost:8095/webhooks")
+        .header("Authorization", &bearer)
+        .json(&json!({"url": "ftp://127.0.0.1:8091/hook"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    // create a webhook for hooked events on a host name - want success
+    let res = client.post("http://localhost:8095/webhooks")
+        .header("Authorization", &bearer)
+        .json(&json!({"url": "http://localhost:8091/hook", "events": ["hooked"], "secret": "shh"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let webhook: broker::Webhook = serde_json::from_str(&res).unwrap();
+
+    // insert an event due now - want it posted to the host and signed
+    let res = client.post("http://localhost:8095/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "hooked", "tenant_id": "e69d88c2-135e-4280-9cd8-d4a5edd86430", "collection_id": "3ca76743-8d99-4d3f-b85c-633ea456f91d", "timestamp": 1578667309, "data": "{}"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let record: broker::Record = serde_json::from_str(&res).unwrap();
+
+    // the post goes to the checked address of the host and keeps the host name in the Host header
+    let (host, signature, body) = tokio::time::timeout(Duration::from_secs(10), rx.recv()).await.unwrap().unwrap();
+    assert_eq!(host, "localhost:8091");
+    let mut mac = Hmac::<Sha256>::new_varkey(b"shh").unwrap();
+    mac.input(body.as_bytes());
+    let expected : String = mac.result().code().iter().map(|b| format!("{:02x}", b)).collect();
+    assert_eq!(signature, format!("sha256={}", expected));
+    assert!(body.contains(&record.event.id.to_string()));
+
+    // no dead letters
+    let res = client.get("http://localhost:8095/webhooks/dead_letters")
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let letters: broker::DeadLetters = serde_json::from_str(&res).unwrap();
+    assert_eq!(letters.dead_letters.len(), 0);
+
+    // remove the webhook - want success
+    let res = client.delete(&format!("http://localhost:8095/webhooks/{}", webhook.id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    drop(broker);
+}
+
+#[tokio::test]
+async fn erased_user_dead_letters() {
+
+    // start a broker on a memory store that dead letters a delivery after its first failure
+    let client = reqwest::Client::new();
+    let broker = common::start_broker(&client, 8096, None, &["--store", "memory", "--admin-secret", "admin", "--webhook-allow-private", "--webhook-attempts", "1"]).await;
+
+    let _posts = failing_stub(8097);
+
+    // create the tenant and two users of it and login as both
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86431";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f91e";
+    let res = client.post("http://localhost:8096/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": tenant_id, "name": "erased hooks"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let mut bearers = Vec::new();
+    let mut user_ids = Vec::new();
+    for username in vec!["erasedhook1", "erasedhook2"] {
+        let res = client.post("http://localhost:8096/users")
+            .json(&json!({"username": username, "password": "rust", "collection_id": collection_id, "tenant_id": tenant_id}))
+            .send().await.unwrap()
+            .text().await.unwrap();
+        let created : serde_json::Value = serde_json::from_str(&res).unwrap();
+        user_ids.push(created["id"].as_str().unwrap().to_owned());
+        let res = client.post("http://localhost:8096/login")
+            .json(&json!({"username": username, "password": "rust"}))
+            .send().await.unwrap()
+            .text().await.unwrap();
+        let token: broker::Token = serde_json::from_str(&res).unwrap();
+        bearers.push(format!("Bearer {}", token.jwt));
+    }
+
+    // create a webhook to the failing stub and insert an event of user 1 due now - want it dead lettered
+    let res = client.post("http://localhost:8096/webhooks")
+        .header("Authorization", &bearers[0])
+        .json(&json!({"url": "http://127.0.0.1:8097/failing"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.post("http://localhost:8096/insert")
+        .header("Authorization", &bearers[0])
+        .json(&json!({"event": "erased", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": 1578667309, "data": {"private": true}}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let record: broker::Record = serde_json::from_str(&res).unwrap();
+    let dead_letters = |bearer: String| {
+        let request = client.get("http://localhost:8096/webhooks/dead_letters").header("Authorization", bearer);
+        async move {
+            let letters : broker::DeadLetters = serde_json::from_str(&request.send().await.unwrap().text().await.unwrap()).unwrap();
+            letters.dead_letters
+        }
+    };
+    let mut letters = dead_letters(bearers[1].clone()).await;
+    for _ in 0..100 {
+        if letters.len() > 0 {
+            break
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        letters = dead_letters(bearers[1].clone()).await;
+    }
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].event.user_id.to_string(), user_ids[0]);
+
+    // erase user 1 keeping its events - want the dead letter anonymized too
+    let res = client.delete(&format!("http://localhost:8096/admin/users/{}?events=anonymize", user_ids[0]))
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let letters = dead_letters(bearers[1].clone()).await;
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].event.id, record.event.id);
+    assert!(letters[0].event.user_id.is_nil());
+
+    drop(broker);
+}
+
+#[tokio::test]
+async fn purged_tenant_deliveries() {
+
+    // start a broker on a memory store that retries a failed delivery an hour later
+    let client = reqwest::Client::new();
+    let broker = common::start_broker(&client, 8098, None, &["--store", "memory", "--admin-secret", "admin", "--webhook-allow-private", "--webhook-backoff", "3600"]).await;
+    let mut posts = failing_stub(8099);
+
+    // create the tenant and a user of it and login
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86432";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f91f";
+    let res = client.post("http://localhost:8098/admin/tenants")
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": tenant_id, "name": "purged hooks"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let _ = client.post("http://localhost:8098/users")
+        .json(&json!({"username": "purgedhook1", "password": "rust", "collection_id": collection_id, "tenant_id": tenant_id}))
+        .send().await.unwrap();
+    let res = client.post("http://localhost:8098/login")
+        .json(&json!({"username": "purgedhook1", "password": "rust"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    let bearer = format!("Bearer {}", token.jwt);
+
+    // create a webhook to the failing stub and insert an event due now - want it posted once and queued again
+    let res = client.post("http://localhost:8098/webhooks")
+        .header("Authorization", &bearer)
+        .json(&json!({"url": "http://127.0.0.1:8099/failing"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.post("http://localhost:8098/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "purged", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": 1578667309, "data": {"private": true}}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let record: broker::Record = serde_json::from_str(&res).unwrap();
+    let body = tokio::time::timeout(Duration::from_secs(10), posts.recv()).await.unwrap().unwrap();
+    assert!(body.contains(&record.event.id.to_string()));
+    tokio::time::delay_for(Duration::from_millis(500)).await;
+
+    // purge the tenant and wait for the purge - want success
+    let res = client.post(&format!("http://localhost:8098/admin/tenants/{}/purge", tenant_id))
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 202);
+    let mut status = "running".to_owned();
+    for _ in 0..100 {
+        let res = client.get(&format!("http://localhost:8098/admin/tenants/{}/purge", tenant_id))
+            .header("Authorization", "Admin admin")
+            .send().await.unwrap();
+        status = serde_json::from_str::<broker::Purge>(&res.text().await.unwrap()).unwrap().status;
+        if status != "running" {
+            break
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+    }
+    assert_eq!(status, "done");
+
+    // backup the store - want no trace of the queued delivery
+    let snapshot = client.get("http://localhost:8098/admin/backup")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .text().await.unwrap();
+    assert!(!snapshot.contains(&record.event.id.to_string()));
+
+    drop(broker);
+}
+
+// create a tenant on the broker at a port with a user of it and login - the bearer of the user
+async fn login_hooks(client: &reqwest::Client, port: u16, tenant_id: &str, username: &str, collection_id: &str) -> String {
+    let res = client.post(&format!("http://localhost:{}/admin/tenants", port))
+        .header("Authorization", "Admin admin")
+        .json(&json!({"id": tenant_id, "name": username}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let _ = client.post(&format!("http://localhost:{}/users", port))
+        .json(&json!({"username": username, "password": "rust", "collection_id": collection_id, "tenant_id": tenant_id}))
+        .send().await.unwrap();
+    let res = client.post(&format!("http://localhost:{}/login", port))
+        .json(&json!({"username": username, "password": "rust"}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let token: broker::Token = serde_json::from_str(&res).unwrap();
+    format!("Bearer {}", token.jwt)
+}
+
+#[tokio::test]
+async fn failed_deliveries() {
+
+    // start a broker on a memory store that retries a failed delivery after 1 then 2 seconds and dead letters it after the third attempt
+    let client = reqwest::Client::new();
+    let broker = common::start_broker(&client, 8100, None, &["--store", "memory", "--admin-secret", "admin", "--webhook-allow-private", "--webhook-backoff", "1", "--webhook-attempts", "3"]).await;
+    let mut posts = failing_stub(8101);
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86433";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f920";
+    let bearer = login_hooks(&client, 8100, tenant_id, "failedhook1", collection_id).await;
+
+    // create a webhook for retried events to the failing stub - want success
+    let res = client.post("http://localhost:8100/webhooks")
+        .header("Authorization", &bearer)
+        .json(&json!({"url": "http://127.0.0.1:8101/failing", "events": ["retried"]}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+
+    // insert an ignored and a retried event due now - want only the retried event posted
+    let mut records = Vec::new();
+    for event in vec!["ignored", "retried"] {
+        let res = client.post("http://localhost:8100/insert")
+            .header("Authorization", &bearer)
+            .json(&json!({"event": event, "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": 1578667309, "data": {}}))
+            .send().await.unwrap()
+            .text().await.unwrap();
+        let record: broker::Record = serde_json::from_str(&res).unwrap();
+        records.push(record.event);
+    }
+
+    // the 3 attempts - want each posted the retried event and the wait doubled after each failure
+    let mut posted_at = Vec::new();
+    for _ in 0..3 {
+        let body = tokio::time::timeout(Duration::from_secs(10), posts.recv()).await.unwrap().unwrap();
+        posted_at.push(std::time::Instant::now());
+        assert!(body.contains(&records[1].id.to_string()));
+        assert!(!body.contains(&records[0].id.to_string()));
+    }
+    for (i, backoff) in vec![1, 2].into_iter().enumerate() {
+        let waited = posted_at[i + 1] - posted_at[i];
+        assert!(waited >= Duration::from_millis(backoff * 1000 - 500) && waited <= Duration::from_millis(backoff * 1000 + 1500), "waited {:?} for a backoff of {}s", waited, backoff);
+    }
+
+    // the dead letters - want the retried event after its last attempt
+    let dead_letters = || {
+        let request = client.get("http://localhost:8100/webhooks/dead_letters").header("Authorization", &bearer);
+        async move {
+            let letters : broker::DeadLetters = serde_json::from_str(&request.send().await.unwrap().text().await.unwrap()).unwrap();
+            letters.dead_letters
+        }
+    };
+    let mut letters = dead_letters().await;
+    for _ in 0..100 {
+        if letters.len() > 0 {
+            break
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        letters = dead_letters().await;
+    }
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].event.id, records[1].id);
+    assert_eq!(letters[0].attempts, 3);
+    assert_eq!(letters[0].last_error, Some("webhook responded 500 Internal Server Error".to_owned()));
+
+    // retry the dead letter - want it off the dead letters and posted again
+    let res = client.post(&format!("http://localhost:8100/webhooks/dead_letters/{}", letters[0].id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    assert_eq!(dead_letters().await.len(), 0);
+    let body = tokio::time::timeout(Duration::from_secs(10), posts.recv()).await.unwrap().unwrap();
+    assert!(body.contains(&records[1].id.to_string()));
+
+    // wait for its attempts to run out again and delete it - want no dead letters and no more posts
+    for _ in 0..2 {
+        let _ = tokio::time::timeout(Duration::from_secs(10), posts.recv()).await.unwrap().unwrap();
+    }
+    let mut letters = dead_letters().await;
+    for _ in 0..100 {
+        if letters.len() > 0 {
+            break
+        }
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        letters = dead_letters().await;
+    }
+    assert_eq!(letters.len(), 1);
+    let res = client.delete(&format!("http://localhost:8100/webhooks/dead_letters/{}", letters[0].id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    assert_eq!(dead_letters().await.len(), 0);
+    assert!(tokio::time::timeout(Duration::from_secs(3), posts.recv()).await.is_err());
+
+    // delete an unknown dead letter - want failure
+    let res = client.delete(&format!("http://localhost:8100/webhooks/dead_letters/{}", letters[0].id))
+        .header("Authorization", &bearer)
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 400);
+
+    drop(broker);
+}
+
+#[tokio::test]
+async fn delivery_backoff_cap() {
+
+    // start a broker on a memory store with a backoff longer than a day
+    let client = reqwest::Client::new();
+    let broker = common::start_broker(&client, 8102, None, &["--store", "memory", "--admin-secret", "admin", "--webhook-allow-private", "--webhook-backoff", "100000"]).await;
+    let mut posts = failing_stub(8103);
+
+    let tenant_id = "e69d88c2-135e-4280-9cd8-d4a5edd86434";
+    let collection_id = "3ca76743-8d99-4d3f-b85c-633ea456f921";
+    let bearer = login_hooks(&client, 8102, tenant_id, "cappedhook1", collection_id).await;
+
+    // create a webhook to the failing stub and insert an event due now - want it posted once
+    let res = client.post("http://localhost:8102/webhooks")
+        .header("Authorization", &bearer)
+        .json(&json!({"url": "http://127.0.0.1:8103/failing"}))
+        .send().await.unwrap()
+        .status();
+    assert_eq!(res, 200);
+    let res = client.post("http://localhost:8102/insert")
+        .header("Authorization", &bearer)
+        .json(&json!({"event": "capped", "tenant_id": tenant_id, "collection_id": collection_id, "timestamp": 1578667309, "data": {}}))
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let record: broker::Record = serde_json::from_str(&res).unwrap();
+    let _ = tokio::time::timeout(Duration::from_secs(10), posts.recv()).await.unwrap().unwrap();
+    tokio::time::delay_for(Duration::from_millis(500)).await;
+
+    // backup the store - want the delivery queued again a day later instead of after the backoff
+    let snapshot = client.get("http://localhost:8102/admin/backup")
+        .header("Authorization", "Admin admin")
+        .send().await.unwrap()
+        .text().await.unwrap();
+    let now = broker::get_ntp_time();
+    let queued : Vec<broker::Delivery> = snapshot.lines().filter_map(|line| match serde_json::from_str(line).unwrap() {
+        broker::BackupLine::Record{key, value} if key.starts_with("_d_") => Some(serde_json::from_str(&value).unwrap()),
+        _ => None
+    }).collect();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].event.id, record.event.id);
+    assert_eq!(queued[0].attempts, 1);
+    assert!(queued[0].next_at <= now + 86400 && queued[0].next_at > now + 86400 - 10);
+
+    drop(broker);
+}